@@ -50,11 +50,24 @@
 #include <stdlib.h>
 #include <stdbool.h>
 
-typedef enum {
-  HeadphoneButton_Play,
-  HeadphoneButton_Up,
-  HeadphoneButton_Down,
-} HeadphoneButton;
+#define MAX_BUTTON_CODE 65535
+
+typedef uint32_t ButtonCode;
+
+typedef ButtonCode HeadphoneButton;
+#define HeadphoneButton_Play 0
+#define HeadphoneButton_Up 1
+#define HeadphoneButton_Down 2
+#define HeadphoneButton_PlayLong 65536
+#define HeadphoneButton_UpLong 65537
+#define HeadphoneButton_DownLong 65538
+#define HeadphoneButton_PlayDouble 131072
+#define HeadphoneButton_UpDouble 131073
+#define HeadphoneButton_DownDouble 131074
+#define HeadphoneButton_PlayUp 262147
+#define HeadphoneButton_PlayDown 262149
+#define HeadphoneButton_UpDown 262150
+#define HeadphoneButton_PlayUpDown 262151
 
 typedef enum {
   ModeChange_Activated,
@@ -71,11 +84,18 @@ typedef struct {
   char *license;
 } Args;
 
-typedef uint16_t Milliseconds;
+typedef uint32_t Milliseconds;
 
 typedef struct {
   Args args;
   Milliseconds timeout;
+  Milliseconds long_press;
+  Milliseconds double_press;
+  Milliseconds chord_window;
+  Milliseconds repeat_interval;
+  Milliseconds key_delay;
+  Milliseconds mode_timeout;
+  char *escape;
 } Config;
 
 typedef struct {
@@ -83,8 +103,21 @@ typedef struct {
   size_t length;
 } Trigger;
 
+typedef struct {
+  const char *name;
+  Trigger trigger;
+} ModeInfo;
+
+typedef uint64_t Timestamp;
+
 extern void dkess_press_key(int16_t key, CGEventFlags modifier_flags);
 
+void dome_key_button_press(State *state, HeadphoneButton button, Timestamp timestamp, const char *device, void (*on_mode_change)(ModeChange, const ModeInfo*));
+
+void dome_key_button_release(State *state, HeadphoneButton button, Timestamp timestamp);
+
+void dome_key_button_tick(State *state, Timestamp timestamp, void (*on_mode_change)(ModeChange, const ModeInfo*));
+
 void dome_key_config_free(Config *ptr);
 
 Config *dome_key_config_get(void);
@@ -95,12 +128,18 @@ void dome_key_logger_init(void);
 
 Config *dome_key_parse_args(const char *const *args, size_t length, Config *config_ptr);
 
-void dome_key_run_key_action(State *state, Trigger trigger, void (*on_mode_change)(ModeChange));
+void dome_key_run_key_action(State *state, Trigger trigger, const char *device, void (*on_mode_change)(ModeChange, const ModeInfo*));
+
+ModeInfo dome_key_state_active_mode(const State *ptr);
 
 void dome_key_state_free(State *ptr);
 
+void dome_key_state_load_config(State *ptr, const Config *config);
+
 void dome_key_state_load_map_group(State *ptr);
 
 State *dome_key_state_new(void);
 
+void dome_key_tick(State *state, void (*on_mode_change)(ModeChange, const ModeInfo*));
+
 #endif /* DOME_KEY_MAP_H */
@@ -44,16 +44,20 @@
 use std::ffi::CString;
 use std::fs;
 use std::ptr;
+use std::result;
 
 use exitcode;
 use libc::c_char;
 use getopts::Options;
+use serde::{Deserialize, Deserializer};
+use serde::de;
 use toml;
 use xdg;
 
 use errors::*;
+use key_name::KEY_NAMES;
 
-type Milliseconds = u16;
+pub type Milliseconds = u32;
 
 #[repr(C)]
 pub struct Args {
@@ -83,6 +87,36 @@ pub struct Config {
     #[serde(skip)]
     pub args: Args,
     pub timeout: Milliseconds,
+
+    /// Buttons held down for at least this many milliseconds are long
+    /// presses.
+    pub long_press: Milliseconds,
+
+    /// Two presses of the same button within this many milliseconds are a
+    /// double press.
+    pub double_press: Milliseconds,
+
+    /// Buttons pressed within this many milliseconds of each other while
+    /// both are held down form a chord.
+    pub chord_window: Milliseconds,
+
+    /// Interval between runs of a held `repeat` mapping that doesn't set its
+    /// own.
+    pub repeat_interval: Milliseconds,
+
+    /// Pause for this many milliseconds between keys in a map action.
+    pub key_delay: Milliseconds,
+
+    /// Leave a mode after this many milliseconds without a button press. A
+    /// value of 0 keeps modes active until they're deactivated.
+    pub mode_timeout: Milliseconds,
+
+    /// A trigger like `"<play><play><play>"` that leaves any active mode.
+    /// Takes precedence over an `escape` directive in the mappings file.
+    /// Like that directive, it's rejected if a mapping, including a default
+    /// one, uses the same trigger. Null if not set.
+    #[serde(deserialize_with = "deserialize_escape")]
+    pub escape: *mut c_char,
 }
 
 impl Default for Config {
@@ -90,10 +124,30 @@ impl Default for Config {
         Config {
             args: Args::default(),
             timeout: 500,
+            long_press: 600,
+            double_press: 300,
+            chord_window: 50,
+            repeat_interval: 100,
+            key_delay: 0,
+            mode_timeout: 0,
+            escape: ptr::null_mut(),
         }
     }
 }
 
+fn deserialize_escape<'de, D>(
+    deserializer: D,
+) -> result::Result<*mut c_char, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let trigger = String::deserialize(deserializer)?;
+
+    CString::new(trigger)
+        .map(|trigger| trigger.into_raw())
+        .map_err(de::Error::custom)
+}
+
 fn print_usage(opts: Options) {
     let brief = "Usage: dome-key [options]";
     print!("{}", opts.usage(&brief));
@@ -101,6 +155,19 @@ fn print_usage(opts: Options) {
     ::std::process::exit(exitcode::OK);
 }
 
+/// Print the key names usable in map actions, one per line with any aliases.
+fn print_key_names() {
+    for key in KEY_NAMES {
+        if key.aliases.is_empty() {
+            println!("{}", key.name);
+        } else {
+            println!("{:<20}{}", key.name, key.aliases.join(", "));
+        }
+    }
+
+    ::std::process::exit(exitcode::OK);
+}
+
 pub fn parse_args<'a>(args: &[String], config: &'a mut Config) -> &'a mut Config {
     let mut opts = Options::new();
 
@@ -113,6 +180,7 @@ pub fn parse_args<'a>(args: &[String], config: &'a mut Config) -> &'a mut Config
         "register the software using a license plist file",
         "FILE"
     );
+    opts.optflag("", "list-keys", "print the key names usable in mappings");
     opts.optflag("v", "version", "print the program version");
     opts.optflag("h", "help", "print this help menu");
 
@@ -126,6 +194,11 @@ pub fn parse_args<'a>(args: &[String], config: &'a mut Config) -> &'a mut Config
         return config;
     }
 
+    if matches.opt_present("list-keys") {
+        print_key_names();
+        return config;
+    }
+
     if matches.opt_present("audio") {
         config.args.audio = true;
     }
@@ -0,0 +1,207 @@
+// Copyright (c) 2018 Teddy Wing
+//
+// This file is part of DomeKey.
+//
+// *Purchasing policy notice:* All users of the software are expected to
+// purchase a license from Teddy Wing unless they have a good reason not to
+// pay. Users who can't purchase a license may apply to receive one for free
+// at inquiry@domekey.teddywing.com. Users are free to:
+//
+// * download, build, and modify the app;
+// * share the modified source code;
+// * share the purchased or custom-built binaries (with unmodified license
+//   and contact info), provided that the purchasing policy is explained to
+//   all potential users.
+//
+// This software is available under a modified version of the Open Community
+// Indie Software License:
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose is hereby granted, subject to the following conditions:
+//
+// * all copies retain the above copyright notice, the above purchasing
+//   policy notice and this permission notice unmodified;
+//
+// * all copies retain the name of the software (DomeKey), the name of the
+//   author (Teddy Wing), and contact information (including, but not limited
+//   to, inquiry@domekey.teddywing.com, and domekey.teddywing.com URLs)
+//   unmodified;
+//
+// * no fee is charged for distribution of the software;
+//
+// * the best effort is made to explain the purchasing policy to all users of
+//   the software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", AND THE AUTHOR AND COPYRIGHT HOLDERS
+// DISCLAIM ALL WARRANTIES, EXPRESS OR IMPLIED, WITH REGARD TO THIS SOFTWARE,
+// INCLUDING BUT NOT LIMITED TO WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE. IN NO EVENT SHALL THE AUTHOR OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY
+// DAMAGES WHATSOEVER RESULTING FROM LOSS OF USE, DATA, OR PROFITS, WHETHER
+// IN AN ACTION OF CONTRACT, NEGLIGENCE, OR OTHER TORTIOUS ACTION, ARISING
+// OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use toml;
+use xdg;
+
+use errors::*;
+
+/// Positions of `toggle` and `cycle` mappings by their key. The position is
+/// the index of the action to run on the next press.
+pub type CyclePositions = HashMap<String, usize>;
+
+const POSITIONS_FILE: &'static str = "cycles.toml";
+
+/// Positions of the cycles in a map group.
+#[derive(Debug, Default)]
+pub struct Cycles {
+    pub positions: CyclePositions,
+
+    /// Contents of the positions file. Keeps the positions of cycles that
+    /// aren't in the current mappings, so saving doesn't lose them.
+    saved: CyclePositions,
+}
+
+impl Cycles {
+    /// Start the `persist` cycles keyed in `persistent` from their `saved`
+    /// positions. Other cycles start from their first action.
+    pub fn with_saved(saved: CyclePositions, persistent: &[&str]) -> Cycles {
+        let positions = persistent
+            .iter()
+            .filter_map(|key|
+                saved.get(*key).map(|position| (key.to_string(), *position))
+            )
+            .collect();
+
+        Cycles {
+            positions: positions,
+            saved: saved,
+        }
+    }
+
+    /// Get the index of the action to run for the cycle `key` with `count`
+    /// actions, and move its position to the next action.
+    pub fn advance(&mut self, key: &str, count: usize) -> usize {
+        let position = self.positions.entry(key.to_owned()).or_insert(0);
+
+        // The mappings may have changed since the position was saved
+        let index = *position % count;
+        *position = (index + 1) % count;
+
+        index
+    }
+
+    /// Save the position of the `persist` cycle `key`.
+    pub fn save(&mut self, key: &str) -> Result<()> {
+        if let Some(&position) = self.positions.get(key) {
+            self.saved.insert(key.to_owned(), position);
+        }
+
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("dome-key")
+            .chain_err(|| "failed to get XDG base directories")?;
+        let path = xdg_dirs.place_data_file(POSITIONS_FILE)
+            .chain_err(|| "failed to get cycle positions file path")?;
+
+        write_positions(&path, &self.saved)
+    }
+}
+
+/// Read the positions of `persist` cycles saved by earlier runs.
+pub fn load_positions() -> Result<CyclePositions> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("dome-key")
+        .chain_err(|| "failed to get XDG base directories")?;
+
+    match xdg_dirs.find_data_file(POSITIONS_FILE) {
+        Some(path) => read_positions(&path),
+        None => Ok(HashMap::new()),
+    }
+}
+
+fn read_positions(path: &Path) -> Result<CyclePositions> {
+    let positions = fs::read_to_string(path)
+        .chain_err(|| "failed to read cycle positions")?;
+
+    toml::from_str(&positions)
+        .chain_err(|| "failed to parse cycle positions")
+}
+
+/// Write `positions` to a temporary file and move it over `path`, so a
+/// failed write can't leave a truncated positions file.
+fn write_positions(path: &Path, positions: &CyclePositions) -> Result<()> {
+    let positions = toml::to_string(positions)
+        .chain_err(|| "failed to serialize cycle positions")?;
+    let temp_path = path.with_extension("toml.tmp");
+
+    fs::write(&temp_path, positions)
+        .chain_err(|| "failed to write cycle positions")?;
+
+    fs::rename(&temp_path, path)
+        .chain_err(|| "failed to replace cycle positions file")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn advance_moves_through_actions_and_wraps_around() {
+        let mut cycles = Cycles::default();
+
+        assert_eq!(cycles.advance("0", 3), 0);
+        assert_eq!(cycles.advance("0", 3), 1);
+        assert_eq!(cycles.advance("1", 2), 0);
+        assert_eq!(cycles.advance("0", 3), 2);
+        assert_eq!(cycles.advance("0", 3), 0);
+    }
+
+    #[test]
+    fn advance_wraps_positions_saved_for_more_actions() {
+        let mut saved = CyclePositions::new();
+        saved.insert("0".to_owned(), 4);
+
+        let mut cycles = Cycles::with_saved(saved, &["0"]);
+
+        assert_eq!(cycles.advance("0", 3), 1);
+        assert_eq!(cycles.positions["0"], 2);
+    }
+
+    #[test]
+    fn with_saved_only_starts_persist_cycles_from_saved_positions() {
+        let mut saved = CyclePositions::new();
+        saved.insert("0".to_owned(), 1);
+        saved.insert("1".to_owned(), 1);
+
+        let mut cycles = Cycles::with_saved(saved, &["1"]);
+
+        assert_eq!(cycles.advance("0", 2), 0);
+        assert_eq!(cycles.advance("1", 2), 1);
+    }
+
+    #[test]
+    fn write_positions_replaces_positions_file() {
+        let dir = env::temp_dir()
+            .join(format!("dome-key-cycles-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(POSITIONS_FILE);
+        fs::write(&path, "old = 1").unwrap();
+
+        let mut positions = CyclePositions::new();
+        positions.insert("0-1".to_owned(), 2);
+
+        write_positions(&path, &positions).unwrap();
+
+        assert_eq!(read_positions(&path).unwrap(), positions);
+        assert!(!path.with_extension("toml.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
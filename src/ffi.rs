@@ -44,18 +44,25 @@
 use std::ffi::{CStr, CString};
 use std::fs;
 use std::ptr;
+use std::rc::Rc;
 use std::slice;
+use std::time::Instant;
 
 use libc::{c_char, size_t};
 use stderrlog;
 use xdg;
 
-use {HeadphoneButton, MapGroup};
-use config::{self, Config};
+use {HeadphoneButton, MapGroup, parse_trigger};
+use clock::{Clock, SystemClock};
+use config::{self, Config, Milliseconds};
+use cycle::{self, CyclePositions, Cycles};
 use errors::*;
-use map::{ModeChange, run_key_action};
+use map::{ModeChange, ModeInfo, check_mode_timeout, run_key_action};
+use press::{self, Presses, Timestamp};
 use trial;
 
+/// A sequence of buttons. Buttons are numeric codes, so generic buttons from
+/// other devices can be passed alongside the `HeadphoneButton` constants.
 #[repr(C)]
 #[derive(Debug)]
 pub struct Trigger {
@@ -63,11 +70,72 @@ pub struct Trigger {
     pub length: size_t,
 }
 
-#[derive(Default)]
 pub struct State {
     pub in_mode: Option<Vec<HeadphoneButton>>,
-    pub map_group: Option<MapGroup>,
+    pub map_group: Option<Rc<MapGroup>>,
     mappings_str: String,
+
+    pub timeout: Milliseconds,
+    pub long_press: Milliseconds,
+    pub double_press: Milliseconds,
+    pub chord_window: Milliseconds,
+    pub repeat_interval: Milliseconds,
+    pub presses: Presses,
+
+    pub key_delay: Milliseconds,
+
+    pub mode_timeout: Milliseconds,
+    pub last_mode_activity: Option<Instant>,
+    pub clock: Box<dyn Clock>,
+
+    /// Escape trigger from the config file. Overrides the one in the
+    /// mappings.
+    pub escape: Option<Vec<HeadphoneButton>>,
+
+    pub device: Option<CString>,
+
+    /// Positions of `toggle` and `cycle` mappings.
+    pub cycles: Cycles,
+}
+
+impl State {
+    /// The device that sent the current trigger, if the host identified it.
+    pub fn device(&self) -> Option<&CStr> {
+        self.device.as_ref().map(|device| device.as_c_str())
+    }
+}
+
+/// Settings start with the defaults from `Config`, so a host that doesn't
+/// load a config still gets working press timings.
+impl Default for State {
+    fn default() -> Self {
+        let config = Config::default();
+
+        State {
+            in_mode: None,
+            map_group: None,
+            mappings_str: String::new(),
+
+            timeout: config.timeout,
+            long_press: config.long_press,
+            double_press: config.double_press,
+            chord_window: config.chord_window,
+            repeat_interval: config.repeat_interval,
+            presses: Presses::default(),
+
+            key_delay: config.key_delay,
+
+            mode_timeout: config.mode_timeout,
+            last_mode_activity: None,
+            clock: Box::new(SystemClock),
+
+            escape: None,
+
+            device: None,
+
+            cycles: Cycles::default(),
+        }
+    }
 }
 
 #[no_mangle]
@@ -116,17 +184,35 @@ pub extern "C" fn dome_key_state_load_map_group(ptr: *mut State) {
                     };
 
                     state.map_group = match MapGroup::parse(&state.mappings_str) {
-                        Ok(mut map_group) => {
-                            Some(map_group)
+                        Ok(map_group) => {
+                            Some(Rc::new(map_group))
                         },
                         Err(e) => {
                             error!("In 'mappings.dkmap': {}", e);
                             None
                         },
                     };
+
+                    check_config_escape(state);
+
+                    let saved = match cycle::load_positions() {
+                        Ok(positions) => positions,
+                        Err(e) => {
+                            error!("{}", e);
+
+                            CyclePositions::new()
+                        },
+                    };
+                    let persistent = state.map_group
+                        .as_ref()
+                        .map_or(Vec::new(), |map_group|
+                            map_group.persistent_cycles()
+                        );
+
+                    state.cycles = Cycles::with_saved(saved, &persistent);
                 },
                 None => {
-                    state.map_group = Some(MapGroup::default());
+                    state.map_group = Some(Rc::new(MapGroup::default()));
 
                     match xdg_dirs.get_config_home().to_str() {
                         Some(config_home) => {
@@ -148,11 +234,96 @@ pub extern "C" fn dome_key_state_load_map_group(ptr: *mut State) {
     }
 }
 
+/// Copy settings that affect how mappings are run from `config` into `State`.
+#[no_mangle]
+pub extern "C" fn dome_key_state_load_config(
+    ptr: *mut State,
+    config: *const Config,
+) {
+    let state = unsafe {
+        assert!(!ptr.is_null());
+        &mut *ptr
+    };
+
+    let config = unsafe {
+        assert!(!config.is_null());
+        &*config
+    };
+
+    state.timeout = config.timeout;
+    state.long_press = config.long_press;
+    state.double_press = config.double_press;
+    state.chord_window = config.chord_window;
+    state.repeat_interval = config.repeat_interval;
+    state.key_delay = config.key_delay;
+    state.mode_timeout = config.mode_timeout;
+
+    state.escape = if config.escape.is_null() {
+        None
+    } else {
+        let escape = unsafe { CStr::from_ptr(config.escape) }.to_string_lossy();
+
+        match parse_trigger(&escape) {
+            Ok(trigger) => Some(trigger),
+            Err(e) => {
+                error!("In 'config.toml' escape: {}", e);
+                None
+            },
+        }
+    };
+
+    check_config_escape(state);
+}
+
+/// Drop the config file's escape trigger if a mapping uses it, like an
+/// `escape` in the mappings file. The escape is checked before any mapping,
+/// so those mappings could never run.
+fn check_config_escape(state: &mut State) {
+    let result = match (&state.escape, &state.map_group) {
+        (&Some(ref escape), &Some(ref map_group)) =>
+            map_group.check_escape(escape),
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        error!("In 'config.toml' escape: {}", e);
+
+        state.escape = None;
+    }
+}
+
+/// Get the name and trigger of the active mode. If no mode is active, the
+/// name is null and the trigger is empty.
+#[no_mangle]
+pub extern "C" fn dome_key_state_active_mode(ptr: *const State) -> ModeInfo {
+    let state = unsafe {
+        assert!(!ptr.is_null());
+        &*ptr
+    };
+
+    let mode = match (&state.map_group, &state.in_mode) {
+        (&Some(ref map_group), &Some(ref in_mode)) =>
+            map_group.modes.get(in_mode),
+        _ => None,
+    };
+
+    match mode {
+        Some(mode) => ModeInfo::from(mode),
+        None => ModeInfo::default(),
+    }
+}
+
+/// Run the action for `trigger`. `device` identifies the device the trigger
+/// came from, and selects its `device` mappings. It can be null.
 #[no_mangle]
 pub extern "C" fn dome_key_run_key_action(
     state: *mut State,
     trigger: Trigger,
-    on_mode_change: extern "C" fn(mode_change: ModeChange),
+    device: *const c_char,
+    on_mode_change: extern "C" fn(
+        mode_change: ModeChange,
+        mode: *const ModeInfo,
+    ),
 ) {
     let trigger = unsafe {
         assert!(!trigger.buttons.is_null());
@@ -165,12 +336,110 @@ pub extern "C" fn dome_key_run_key_action(
         &mut *state
     };
 
+    set_device(&mut state, device);
+
     match run_key_action(&mut state, trigger, on_mode_change) {
         Ok(_) => (),
         Err(e) => error!("{}", e),
     };
 }
 
+fn set_device(state: &mut State, device: *const c_char) {
+    state.device = if device.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(device) }.to_owned())
+    };
+}
+
+/// Handle time-based events like mode timeouts. Should be called periodically
+/// by the host, for example from a repeating timer.
+#[no_mangle]
+pub extern "C" fn dome_key_tick(
+    state: *mut State,
+    on_mode_change: extern "C" fn(
+        mode_change: ModeChange,
+        mode: *const ModeInfo,
+    ),
+) {
+    let mut state = unsafe {
+        assert!(!state.is_null());
+        &mut *state
+    };
+
+    match check_mode_timeout(&mut state, on_mode_change) {
+        Ok(_) => (),
+        Err(e) => error!("{}", e),
+    };
+}
+
+/// Record a button being pressed down. `timestamp` is in milliseconds, and
+/// only needs to be consistent between calls. `device` can be null, as in
+/// `dome_key_run_key_action`. Mappings with `repeat` run immediately.
+#[no_mangle]
+pub extern "C" fn dome_key_button_press(
+    state: *mut State,
+    button: HeadphoneButton,
+    timestamp: Timestamp,
+    device: *const c_char,
+    on_mode_change: extern "C" fn(
+        mode_change: ModeChange,
+        mode: *const ModeInfo,
+    ),
+) {
+    let mut state = unsafe {
+        assert!(!state.is_null());
+        &mut *state
+    };
+
+    set_device(&mut state, device);
+
+    match press::button_press(&mut state, button, timestamp, on_mode_change) {
+        Ok(_) => (),
+        Err(e) => error!("{}", e),
+    };
+}
+
+/// Record a button being released. Long presses are resolved here using the
+/// timestamp of the matching press, and held `repeat` mappings stop.
+#[no_mangle]
+pub extern "C" fn dome_key_button_release(
+    state: *mut State,
+    button: HeadphoneButton,
+    timestamp: Timestamp,
+) {
+    let mut state = unsafe {
+        assert!(!state.is_null());
+        &mut *state
+    };
+
+    press::button_release(&mut state, button, timestamp);
+}
+
+/// Repeat held `repeat` mappings, resolve pending button presses, and run the
+/// resulting trigger once the trigger timeout has passed. Should be called
+/// periodically with the current time when using `dome_key_button_press` and
+/// `dome_key_button_release`.
+#[no_mangle]
+pub extern "C" fn dome_key_button_tick(
+    state: *mut State,
+    timestamp: Timestamp,
+    on_mode_change: extern "C" fn(
+        mode_change: ModeChange,
+        mode: *const ModeInfo,
+    ),
+) {
+    let mut state = unsafe {
+        assert!(!state.is_null());
+        &mut *state
+    };
+
+    match press::check_presses(&mut state, timestamp, on_mode_change) {
+        Ok(_) => (),
+        Err(e) => error!("{}", e),
+    };
+}
+
 #[no_mangle]
 pub extern "C" fn dome_key_parse_args(
     args: *const *const c_char,
@@ -221,6 +490,10 @@ pub extern "C" fn dome_key_config_free(ptr: *mut Config) {
     if ptr.is_null() { return }
     let config = unsafe { Box::from_raw(ptr) };
 
+    if !config.escape.is_null() {
+        unsafe { CString::from_raw(config.escape); }
+    }
+
     if config.args.license.is_null() { return }
     unsafe { CString::from_raw(config.args.license); }
 }
@@ -229,3 +502,35 @@ pub extern "C" fn dome_key_config_free(ptr: *mut Config) {
 pub extern "C" fn dome_key_do_trial() {
     trial::do_trial();
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_config_with_escape(state: &mut State, escape: &str) {
+        let mut config = Config::default();
+        config.escape = CString::new(escape).unwrap().into_raw();
+
+        dome_key_state_load_config(state, &config);
+
+        unsafe { CString::from_raw(config.escape); }
+    }
+
+    #[test]
+    fn dome_key_state_load_config_rejects_shadowed_escape() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(
+            MapGroup::parse("map <play><play> <Nop>").unwrap()
+        ));
+
+        load_config_with_escape(&mut state, "<play><play>");
+        assert_eq!(state.escape, None);
+
+        load_config_with_escape(&mut state, "<play><up>");
+        assert_eq!(
+            state.escape,
+            Some(vec![HeadphoneButton::Play, HeadphoneButton::Up])
+        );
+    }
+}
@@ -41,7 +41,7 @@
 // IN AN ACTION OF CONTRACT, NEGLIGENCE, OR OTHER TORTIOUS ACTION, ARISING
 // OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
-use core_graphics::event::CGEventFlags;
+use core_graphics::event::{CGEventFlags, CGKeyCode};
 
 #[link(name="dome_key_event_source_simulator", kind="static")]
 extern "C" {
@@ -50,18 +50,28 @@ extern "C" {
 
 pub type NXKey = i16;
 
+/// The largest virtual key code accepted in `<code:N>`.
+pub const MAX_KEY_CODE: CGKeyCode = 0x7F;
+
+/// The largest NX key type accepted in `<nx:N>`.
+pub const MAX_NX_KEY: NXKey = 0x7F;
+
 // /System/Library/Frameworks/IOKit.framework/Versions/A/Headers/hidsystem/ev_keymap.h
 pub const NX_KEYTYPE_SOUND_UP: NXKey = 0;
 pub const NX_KEYTYPE_SOUND_DOWN: NXKey = 1;
 pub const NX_KEYTYPE_BRIGHTNESS_UP: NXKey = 2;
 pub const NX_KEYTYPE_BRIGHTNESS_DOWN: NXKey = 3;
+pub const NX_KEYTYPE_CAPS_LOCK: NXKey = 4;
 pub const NX_KEYTYPE_HELP: NXKey = 5;
 pub const NX_POWER_KEY: NXKey = 6;
 pub const NX_KEYTYPE_MUTE: NXKey = 7;
+pub const NX_UP_ARROW_KEY: NXKey = 8;
+pub const NX_DOWN_ARROW_KEY: NXKey = 9;
 pub const NX_KEYTYPE_NUM_LOCK: NXKey = 10;
 
 pub const NX_KEYTYPE_CONTRAST_UP: NXKey = 11;
 pub const NX_KEYTYPE_CONTRAST_DOWN: NXKey = 12;
+pub const NX_KEYTYPE_LAUNCH_PANEL: NXKey = 13;
 pub const NX_KEYTYPE_EJECT: NXKey = 14;
 pub const NX_KEYTYPE_VIDMIRROR: NXKey = 15;
 
@@ -75,6 +85,44 @@ pub const NX_KEYTYPE_ILLUMINATION_UP: NXKey = 21;
 pub const NX_KEYTYPE_ILLUMINATION_DOWN: NXKey = 22;
 pub const NX_KEYTYPE_ILLUMINATION_TOGGLE: NXKey = 23;
 
+// Virtual key codes for keys that `autopilot::key::KeyCode` doesn't have.
+// /System/Library/Frameworks/Carbon.framework/Versions/A/Frameworks/HIToolbox.framework/Versions/A/Headers/Events.h
+pub const KVK_RIGHT_COMMAND: CGKeyCode = 0x36;
+pub const KVK_RIGHT_SHIFT: CGKeyCode = 0x3C;
+pub const KVK_RIGHT_OPTION: CGKeyCode = 0x3D;
+pub const KVK_RIGHT_CONTROL: CGKeyCode = 0x3E;
+pub const KVK_FUNCTION: CGKeyCode = 0x3F;
+
+pub const KVK_F13: CGKeyCode = 0x69;
+pub const KVK_F14: CGKeyCode = 0x6B;
+pub const KVK_F15: CGKeyCode = 0x71;
+pub const KVK_F16: CGKeyCode = 0x6A;
+pub const KVK_F17: CGKeyCode = 0x40;
+pub const KVK_F18: CGKeyCode = 0x4F;
+pub const KVK_F19: CGKeyCode = 0x50;
+pub const KVK_F20: CGKeyCode = 0x5A;
+
+pub const KVK_HELP: CGKeyCode = 0x72;
+
+pub const KVK_ANSI_KEYPAD_DECIMAL: CGKeyCode = 0x41;
+pub const KVK_ANSI_KEYPAD_MULTIPLY: CGKeyCode = 0x43;
+pub const KVK_ANSI_KEYPAD_PLUS: CGKeyCode = 0x45;
+pub const KVK_ANSI_KEYPAD_CLEAR: CGKeyCode = 0x47;
+pub const KVK_ANSI_KEYPAD_DIVIDE: CGKeyCode = 0x4B;
+pub const KVK_ANSI_KEYPAD_ENTER: CGKeyCode = 0x4C;
+pub const KVK_ANSI_KEYPAD_MINUS: CGKeyCode = 0x4E;
+pub const KVK_ANSI_KEYPAD_EQUALS: CGKeyCode = 0x51;
+pub const KVK_ANSI_KEYPAD_0: CGKeyCode = 0x52;
+pub const KVK_ANSI_KEYPAD_1: CGKeyCode = 0x53;
+pub const KVK_ANSI_KEYPAD_2: CGKeyCode = 0x54;
+pub const KVK_ANSI_KEYPAD_3: CGKeyCode = 0x55;
+pub const KVK_ANSI_KEYPAD_4: CGKeyCode = 0x56;
+pub const KVK_ANSI_KEYPAD_5: CGKeyCode = 0x57;
+pub const KVK_ANSI_KEYPAD_6: CGKeyCode = 0x58;
+pub const KVK_ANSI_KEYPAD_7: CGKeyCode = 0x59;
+pub const KVK_ANSI_KEYPAD_8: CGKeyCode = 0x5B;
+pub const KVK_ANSI_KEYPAD_9: CGKeyCode = 0x5C;
+
 
 #[cfg(test)]
 mod tests {
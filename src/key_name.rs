@@ -0,0 +1,646 @@
+// Copyright (c) 2018 Teddy Wing
+//
+// This file is part of DomeKey.
+//
+// *Purchasing policy notice:* All users of the software are expected to
+// purchase a license from Teddy Wing unless they have a good reason not to
+// pay. Users who can't purchase a license may apply to receive one for free
+// at inquiry@domekey.teddywing.com. Users are free to:
+//
+// * download, build, and modify the app;
+// * share the modified source code;
+// * share the purchased or custom-built binaries (with unmodified license
+//   and contact info), provided that the purchasing policy is explained to
+//   all potential users.
+//
+// This software is available under a modified version of the Open Community
+// Indie Software License:
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose is hereby granted, subject to the following conditions:
+//
+// * all copies retain the above copyright notice, the above purchasing
+//   policy notice and this permission notice unmodified;
+//
+// * all copies retain the name of the software (DomeKey), the name of the
+//   author (Teddy Wing), and contact information (including, but not limited
+//   to, inquiry@domekey.teddywing.com, and domekey.teddywing.com URLs)
+//   unmodified;
+//
+// * no fee is charged for distribution of the software;
+//
+// * the best effort is made to explain the purchasing policy to all users of
+//   the software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", AND THE AUTHOR AND COPYRIGHT HOLDERS
+// DISCLAIM ALL WARRANTIES, EXPRESS OR IMPLIED, WITH REGARD TO THIS SOFTWARE,
+// INCLUDING BUT NOT LIMITED TO WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE. IN NO EVENT SHALL THE AUTHOR OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY
+// DAMAGES WHATSOEVER RESULTING FROM LOSS OF USE, DATA, OR PROFITS, WHETHER
+// IN AN ACTION OF CONTRACT, NEGLIGENCE, OR OTHER TORTIOUS ACTION, ARISING
+// OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+use std::collections::HashMap;
+
+use autopilot::key::KeyCode;
+use core_graphics::event::CGKeyCode;
+
+use key_code::{self, NXKey};
+
+/// How a named key is sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyKind {
+    /// A key `autopilot` knows.
+    Code(KeyCode),
+
+    /// A virtual key code, for keys `autopilot` doesn't know.
+    Raw(CGKeyCode),
+
+    /// A media or special key, sent as an NX key type.
+    NX(NXKey),
+}
+
+/// A key name usable in map actions, like `<PageUp>`.
+pub struct KeyName {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub kind: KeyKind,
+}
+
+/// Every key name the parser accepts. The first entry for a key gives its
+/// canonical name.
+pub static KEY_NAMES: &'static [KeyName] = &[
+    KeyName {
+        name: "F1",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F1),
+    },
+    KeyName {
+        name: "F2",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F2),
+    },
+    KeyName {
+        name: "F3",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F3),
+    },
+    KeyName {
+        name: "F4",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F4),
+    },
+    KeyName {
+        name: "F5",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F5),
+    },
+    KeyName {
+        name: "F6",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F6),
+    },
+    KeyName {
+        name: "F7",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F7),
+    },
+    KeyName {
+        name: "F8",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F8),
+    },
+    KeyName {
+        name: "F9",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F9),
+    },
+    KeyName {
+        name: "F10",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F10),
+    },
+    KeyName {
+        name: "F11",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F11),
+    },
+    KeyName {
+        name: "F12",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::F12),
+    },
+    KeyName {
+        name: "F13",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F13),
+    },
+    KeyName {
+        name: "F14",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F14),
+    },
+    KeyName {
+        name: "F15",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F15),
+    },
+    KeyName {
+        name: "F16",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F16),
+    },
+    KeyName {
+        name: "F17",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F17),
+    },
+    KeyName {
+        name: "F18",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F18),
+    },
+    KeyName {
+        name: "F19",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F19),
+    },
+    KeyName {
+        name: "F20",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F20),
+    },
+
+    KeyName {
+        name: "Left",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::LeftArrow),
+    },
+    KeyName {
+        name: "Right",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::RightArrow),
+    },
+    KeyName {
+        name: "Down",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::DownArrow),
+    },
+    KeyName {
+        name: "Up",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::UpArrow),
+    },
+    KeyName {
+        name: "Home",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Home),
+    },
+    KeyName {
+        name: "End",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::End),
+    },
+    KeyName {
+        name: "PageUp",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::PageUp),
+    },
+    KeyName {
+        name: "PageDown",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::PageDown),
+    },
+
+    KeyName {
+        name: "Return",
+        aliases: &["Enter", "CR"],
+        kind: KeyKind::Code(KeyCode::Return),
+    },
+    KeyName {
+        name: "Del",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Delete),
+    },
+    KeyName {
+        name: "BS",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Backspace),
+    },
+    KeyName {
+        name: "Esc",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Escape),
+    },
+    KeyName {
+        name: "CapsLock",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::CapsLock),
+    },
+    KeyName {
+        name: "Tab",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Tab),
+    },
+    KeyName {
+        name: "Space",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Space),
+    },
+    KeyName {
+        name: "Insert",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_HELP),
+    },
+    KeyName {
+        name: "PrintScreen",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_F13),
+    },
+
+    KeyName {
+        name: "Cmd",
+        aliases: &["Command"],
+        kind: KeyKind::Code(KeyCode::Meta),
+    },
+    KeyName {
+        name: "Shift",
+        aliases: &[],
+        kind: KeyKind::Code(KeyCode::Shift),
+    },
+    KeyName {
+        name: "Option",
+        aliases: &["Alt"],
+        kind: KeyKind::Code(KeyCode::Alt),
+    },
+    KeyName {
+        name: "Ctrl",
+        aliases: &["Control"],
+        kind: KeyKind::Code(KeyCode::Control),
+    },
+    KeyName {
+        name: "Fn",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_FUNCTION),
+    },
+    KeyName {
+        name: "RCmd",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_RIGHT_COMMAND),
+    },
+    KeyName {
+        name: "RShift",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_RIGHT_SHIFT),
+    },
+    KeyName {
+        name: "ROption",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_RIGHT_OPTION),
+    },
+    KeyName {
+        name: "RCtrl",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_RIGHT_CONTROL),
+    },
+
+    KeyName {
+        name: "KP0",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_0),
+    },
+    KeyName {
+        name: "KP1",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_1),
+    },
+    KeyName {
+        name: "KP2",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_2),
+    },
+    KeyName {
+        name: "KP3",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_3),
+    },
+    KeyName {
+        name: "KP4",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_4),
+    },
+    KeyName {
+        name: "KP5",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_5),
+    },
+    KeyName {
+        name: "KP6",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_6),
+    },
+    KeyName {
+        name: "KP7",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_7),
+    },
+    KeyName {
+        name: "KP8",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_8),
+    },
+    KeyName {
+        name: "KP9",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_9),
+    },
+    KeyName {
+        name: "KPDecimal",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_DECIMAL),
+    },
+    KeyName {
+        name: "KPPlus",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_PLUS),
+    },
+    KeyName {
+        name: "KPMinus",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_MINUS),
+    },
+    KeyName {
+        name: "KPMultiply",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_MULTIPLY),
+    },
+    KeyName {
+        name: "KPDivide",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_DIVIDE),
+    },
+    KeyName {
+        name: "KPEquals",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_EQUALS),
+    },
+    KeyName {
+        name: "KPEnter",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_ENTER),
+    },
+    KeyName {
+        name: "KPClear",
+        aliases: &[],
+        kind: KeyKind::Raw(key_code::KVK_ANSI_KEYPAD_CLEAR),
+    },
+
+    KeyName {
+        name: "VolumeUp",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_SOUND_UP),
+    },
+    KeyName {
+        name: "VolumeDown",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_SOUND_DOWN),
+    },
+    KeyName {
+        name: "Mute",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_MUTE),
+    },
+    KeyName {
+        name: "BrightnessUp",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_BRIGHTNESS_UP),
+    },
+    KeyName {
+        name: "BrightnessDown",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_BRIGHTNESS_DOWN),
+    },
+    KeyName {
+        name: "Help",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_HELP),
+    },
+    KeyName {
+        name: "Power",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_POWER_KEY),
+    },
+    KeyName {
+        name: "NumLock",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_NUM_LOCK),
+    },
+    KeyName {
+        name: "NXCapsLock",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_CAPS_LOCK),
+    },
+    KeyName {
+        name: "NXUp",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_UP_ARROW_KEY),
+    },
+    KeyName {
+        name: "NXDown",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_DOWN_ARROW_KEY),
+    },
+
+    KeyName {
+        name: "ContrastUp",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_CONTRAST_UP),
+    },
+    KeyName {
+        name: "ContrastDown",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_CONTRAST_DOWN),
+    },
+    KeyName {
+        name: "LaunchPanel",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_LAUNCH_PANEL),
+    },
+    KeyName {
+        name: "Eject",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_EJECT),
+    },
+    KeyName {
+        name: "VidMirror",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_VIDMIRROR),
+    },
+
+    KeyName {
+        name: "Play",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_PLAY),
+    },
+    KeyName {
+        name: "Next",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_NEXT),
+    },
+    KeyName {
+        name: "Previous",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_PREVIOUS),
+    },
+    KeyName {
+        name: "Fast",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_FAST),
+    },
+    KeyName {
+        name: "Rewind",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_REWIND),
+    },
+
+    KeyName {
+        name: "IlluminationUp",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_ILLUMINATION_UP),
+    },
+    KeyName {
+        name: "IlluminationDown",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_ILLUMINATION_DOWN),
+    },
+    KeyName {
+        name: "IlluminationToggle",
+        aliases: &[],
+        kind: KeyKind::NX(key_code::NX_KEYTYPE_ILLUMINATION_TOGGLE),
+    },
+];
+
+/// Keys in `KEY_NAMES` by lowercase name and alias. Build one per parse and
+/// look up every key name in it.
+pub struct KeyIndex(HashMap<String, KeyKind>);
+
+impl KeyIndex {
+    pub fn new() -> Self {
+        KeyIndex(
+            KEY_NAMES
+                .iter()
+                .flat_map(|key|
+                    ::std::iter::once(&key.name)
+                        .chain(key.aliases.iter())
+                        .map(move |name| (name.to_lowercase(), key.kind))
+                )
+                .collect()
+        )
+    }
+
+    /// Find the key with the given name or alias, ignoring case.
+    pub fn lookup(&self, name: &str) -> Option<KeyKind> {
+        self.0.get(&name.to_lowercase()).cloned()
+    }
+}
+
+/// Get the canonical name of a key.
+pub fn name(kind: KeyKind) -> Option<&'static str> {
+    KEY_NAMES
+        .iter()
+        .find(|key| key.kind == kind)
+        .map(|key| key.name)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_finds_names_and_aliases_ignoring_case() {
+        let index = KeyIndex::new();
+
+        assert_eq!(
+            index.lookup("pageup"),
+            Some(KeyKind::Code(KeyCode::PageUp)),
+        );
+        assert_eq!(
+            index.lookup("ENTER"),
+            Some(KeyKind::Code(KeyCode::Return)),
+        );
+        assert_eq!(index.lookup("Nop"), None);
+    }
+
+    #[test]
+    fn name_gets_canonical_name() {
+        assert_eq!(name(KeyKind::Code(KeyCode::Return)), Some("Return"));
+        assert_eq!(name(KeyKind::Raw(key_code::KVK_F13)), Some("F13"));
+        assert_eq!(name(KeyKind::Raw(0x35)), None);
+    }
+
+    #[test]
+    fn key_names_are_unique() {
+        let mut names = KEY_NAMES
+            .iter()
+            .flat_map(|key|
+                ::std::iter::once(&key.name).chain(key.aliases.iter())
+            )
+            .map(|name| name.to_lowercase())
+            .collect::<Vec<_>>();
+        let count = names.len();
+
+        names.sort();
+        names.dedup();
+
+        assert_eq!(names.len(), count);
+    }
+
+    /// Find a key by checking each entry in `KEY_NAMES` in turn, as the
+    /// parser did before the index.
+    fn scan(name: &str) -> Option<KeyKind> {
+        KEY_NAMES
+            .iter()
+            .find(|key|
+                ::std::iter::once(&key.name)
+                    .chain(key.aliases.iter())
+                    .any(|n| n.eq_ignore_ascii_case(name))
+            )
+            .map(|key| key.kind)
+    }
+
+    // Run with `make bench`.
+    #[test]
+    #[ignore]
+    fn bench_lookup_is_faster_than_scanning_key_names() {
+        use std::time::{Duration, Instant};
+
+        let names = KEY_NAMES
+            .iter()
+            .map(|key| key.name.to_uppercase())
+            .collect::<Vec<_>>();
+
+        let time = |find: &dyn Fn(&str) -> Option<KeyKind>| -> Duration {
+            let start = Instant::now();
+
+            for _ in 0..1_000 {
+                for name in &names {
+                    assert!(find(name).is_some());
+                }
+            }
+
+            start.elapsed()
+        };
+
+        // Build the index outside the timing
+        let index = KeyIndex::new();
+
+        let indexed = time(&|name| index.lookup(name));
+        let scanned = time(&scan);
+
+        println!("lookup: {:?}, scan: {:?}", indexed, scanned);
+
+        assert!(indexed < scanned);
+    }
+}
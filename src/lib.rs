@@ -63,6 +63,7 @@ extern crate magic_crypt;
 #[macro_use]
 extern crate quick_error;
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate stderrlog;
@@ -73,14 +74,31 @@ extern crate xdg;
 mod prefix_println;
 
 mod autopilot_internal;
+mod clock;
 mod config;
+mod cycle;
 mod errors;
 mod ffi;
 mod key_code;
+mod key_name;
 mod map;
 mod parser;
+mod press;
 mod trial;
 
-use parser::{Action, HeadphoneButton, MapAction, MapGroup, MapKind};
+use parser::{
+    Action,
+    COMMAND_TIMEOUT,
+    ConflictPolicy,
+    HeadphoneButton,
+    KeyboardKeyWithModifiers,
+    MapAction,
+    MapGroup,
+    MapKind,
+    Mode,
+    ModeSwitch,
+    Step,
+    parse_trigger,
+};
 
 pub use ffi::*;
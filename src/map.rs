@@ -42,12 +42,36 @@
 // OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
 use std::env;
-use std::ffi::OsString;
-use std::process::Command;
+use std::ffi::{CStr, OsString};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use {Action, HeadphoneButton, MapAction, MapKind};
+use autopilot;
+use autopilot::key::Flag;
+use libc::{self, c_char};
+
+use {
+    Action,
+    COMMAND_TIMEOUT,
+    ConflictPolicy,
+    HeadphoneButton,
+    KeyboardKeyWithModifiers,
+    MapAction,
+    MapGroup,
+    MapKind,
+    Mode,
+    ModeSwitch,
+    Step,
+};
+use clock::Clock;
+use config::Milliseconds;
+use cycle::Cycles;
 use errors::*;
-use ffi::State;
+use ffi::{State, Trigger};
 
 #[repr(C)]
 pub enum ModeChange {
@@ -55,76 +79,1492 @@ pub enum ModeChange {
     Deactivated,
 }
 
+/// Identifies the mode passed to the mode change callback. Pointers are only
+/// valid until the mappings are reloaded.
+#[repr(C)]
+pub struct ModeInfo {
+    /// The mode's name, or null if it isn't named.
+    pub name: *const c_char,
+    pub trigger: Trigger,
+}
+
+impl Default for ModeInfo {
+    fn default() -> Self {
+        ModeInfo {
+            name: ptr::null(),
+            trigger: Trigger {
+                buttons: ptr::null(),
+                length: 0,
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a Mode> for ModeInfo {
+    fn from(mode: &Mode) -> Self {
+        ModeInfo {
+            name: mode.name
+                .as_ref()
+                .map_or(ptr::null(), |name| name.as_ptr()),
+            trigger: Trigger {
+                buttons: mode.trigger.as_ptr(),
+                length: mode.trigger.len(),
+            },
+        }
+    }
+}
+
+pub type OnModeChange = extern "C" fn(
+    mode_change: ModeChange,
+    mode: *const ModeInfo,
+);
+
 pub fn run_key_action<'a>(
     state: &mut State,
     trigger: &'a [HeadphoneButton],
-    on_mode_change: extern "C" fn(mode_change: ModeChange),
+    on_mode_change: OnModeChange,
 ) -> Result<()> {
-    match state.map_group {
-        Some(ref map_group) => {
-            let map = map_group.maps.get(trigger);
-            let mode = map_group.modes.get(trigger);
+    check_mode_timeout(state, on_mode_change)?;
 
-            if let Some(in_mode) = state.in_mode.clone() {
-                if let Some(mode) = map_group.modes.get(&in_mode) {
-                    // Deactivate mode by pressing current mode trigger
-                    if &in_mode[..] == trigger {
-                        state.in_mode = None;
+    with_map_group(state, |state, map_group| {
+        let is_escape = state.escape
+            .as_ref()
+            .or(map_group.escape.as_ref())
+            .map_or(false, |escape| &escape[..] == trigger);
 
-                        on_mode_change(ModeChange::Deactivated);
+        if is_escape {
+            return escape_mode(state, map_group, on_mode_change);
+        }
 
-                        return Ok(());
-                    }
+        let mut map = map_group.global_map(state.device(), trigger);
+        let mode = map_group.modes.get(trigger);
+        let mut mode_switch = None;
 
-                    if let Some(map) = mode.get(trigger) {
-                        run_action(&map)?;
+        if map.is_some() && mode.is_some() {
+            match map_group.conflict {
+                ConflictPolicy::MapFirst => (),
+                ConflictPolicy::ModeOnly => map = None,
+                ConflictPolicy::Error =>
+                    bail!("trigger is both a map and a mode"),
+            }
+        }
+
+        if let Some(in_mode) = state.in_mode.clone() {
+            if let Some(current_mode) = map_group.modes.get(&in_mode) {
+                // Deactivate mode by pressing current mode trigger
+                if &in_mode[..] == trigger {
+                    return deactivate_mode(state, current_mode, on_mode_change);
+                }
+
+                state.last_mode_activity = Some(state.clock.now());
+
+                let map = current_mode.maps
+                    .get(trigger)
+                    .or_else(||
+                        fallthrough_map(
+                            map_group,
+                            current_mode,
+                            state.device(),
+                            trigger,
+                        )
+                    );
+
+                if let Some(map) = map {
+                    let result = run_action(
+                        &map,
+                        state.key_delay,
+                        &*state.clock,
+                        &mut state.cycles,
+                    );
+
+                    // Leave a oneshot mode after any mapping it runs,
+                    // including a global one it falls through to
+                    if current_mode.options.oneshot {
+                        deactivate_mode(state, current_mode, on_mode_change)?;
+
+                        return switch_mode(
+                            state,
+                            map_group,
+                            result?,
+                            on_mode_change,
+                        );
                     }
+
+                    mode_switch = result?;
                 }
             }
+        }
 
-            if state.in_mode.is_none() {
-                if let Some(map) = map {
-                    run_action(&map)?;
+        if state.in_mode.is_none() {
+            if let Some(map) = map {
+                mode_switch = run_action(
+                    &map,
+                    state.key_delay,
+                    &*state.clock,
+                    &mut state.cycles,
+                )?;
+            }
+        }
+
+        if let Some(mode) = mode {
+            activate_mode(state, map_group, mode, on_mode_change)?;
+        }
+
+        switch_mode(state, map_group, mode_switch, on_mode_change)
+    })
+}
+
+/// Deactivate the current mode if no buttons have been pressed in it for
+/// longer than its timeout.
+pub fn check_mode_timeout(
+    state: &mut State,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    with_map_group(state, |state, map_group| {
+        if let Some(in_mode) = state.in_mode.clone() {
+            if let Some(mode) = map_group.modes.get(&in_mode) {
+                let timeout = mode.options.timeout
+                    .unwrap_or(state.mode_timeout);
+
+                if timeout == 0 {
+                    return Ok(());
+                }
+
+                if let Some(last_activity) = state.last_mode_activity {
+                    let elapsed = state.clock.now()
+                        .duration_since(last_activity);
+
+                    if elapsed >= Duration::from_millis(timeout as u64) {
+                        return deactivate_mode(state, mode, on_mode_change);
+                    }
                 }
             }
+        }
+
+        Ok(())
+    })
+}
+
+/// Call `f` with a shared reference to the map group in `state`. This allows
+/// `state` to be modified while holding references to the group's mappings.
+/// The group stays in `state`, so `on_mode_change` callbacks can still call
+/// `dome_key_state_active_mode`.
+fn with_map_group<F>(state: &mut State, f: F) -> Result<()>
+where
+    F: FnOnce(&mut State, &MapGroup) -> Result<()>,
+{
+    match state.map_group.clone() {
+        Some(map_group) => f(state, &map_group),
+        None => Ok(()),
+    }
+}
+
+/// Get the mapping that `trigger` would run in the active mode, or the global
+/// mapping if no mode is active.
+pub fn current_map<'a>(
+    state: &State,
+    map_group: &'a MapGroup,
+    trigger: &[HeadphoneButton],
+) -> Option<&'a MapAction> {
+    match state.in_mode {
+        Some(ref in_mode) => match map_group.modes.get(in_mode) {
+            Some(mode) => mode.maps
+                .get(trigger)
+                .or_else(||
+                    fallthrough_map(map_group, mode, state.device(), trigger)
+                ),
+            None => None,
+        },
+        None => map_group.global_map(state.device(), trigger),
+    }
+}
+
+/// Get the global mapping for `trigger` if `mode` falls through to global
+/// mappings.
+fn fallthrough_map<'a>(
+    map_group: &'a MapGroup,
+    mode: &Mode,
+    device: Option<&CStr>,
+    trigger: &[HeadphoneButton],
+) -> Option<&'a MapAction> {
+    if mode.options.fallthrough {
+        map_group.global_map(device, trigger)
+    } else {
+        None
+    }
+}
+
+/// Leave the active mode, returning to top-level mappings. Unlike the mode
+/// trigger, this works no matter how the mode was entered or what it maps.
+fn escape_mode(
+    state: &mut State,
+    map_group: &MapGroup,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    match state.in_mode.take() {
+        Some(in_mode) => match map_group.modes.get(&in_mode) {
+            Some(mode) => deactivate_mode(state, mode, on_mode_change),
+            None => {
+                state.last_mode_activity = None;
 
-            if mode.is_some() {
-                state.in_mode = Some(trigger.to_vec());
+                Ok(())
+            },
+        },
+        None => Ok(()),
+    }
+}
 
-                on_mode_change(ModeChange::Activated);
+/// Apply a mode change requested by a map action.
+fn switch_mode(
+    state: &mut State,
+    map_group: &MapGroup,
+    mode_switch: Option<ModeSwitch>,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    match mode_switch {
+        Some(ModeSwitch::Enter(name)) => {
+            match map_group.mode_by_name(name) {
+                Some(mode) =>
+                    activate_mode(state, map_group, mode, on_mode_change),
+                None => bail!("no mode named '{}'", name.to_string_lossy()),
             }
         },
-        None => (),
-    };
+        Some(ModeSwitch::Exit) => {
+            match state.in_mode.clone() {
+                Some(in_mode) => match map_group.modes.get(&in_mode) {
+                    Some(mode) => deactivate_mode(state, mode, on_mode_change),
+                    None => Ok(()),
+                },
+                None => Ok(()),
+            }
+        },
+        None => Ok(()),
+    }
+}
+
+/// Activate `mode`, deactivating the current mode first. Mode switches
+/// requested by enter and exit actions are ignored to avoid activating modes
+/// in a loop.
+fn activate_mode(
+    state: &mut State,
+    map_group: &MapGroup,
+    mode: &Mode,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    if let Some(in_mode) = state.in_mode.clone() {
+        if in_mode == mode.trigger {
+            return Ok(());
+        }
+
+        if let Some(current_mode) = map_group.modes.get(&in_mode) {
+            deactivate_mode(state, current_mode, on_mode_change)?;
+        }
+    }
+
+    state.in_mode = Some(mode.trigger.clone());
+    state.last_mode_activity = Some(state.clock.now());
+
+    on_mode_change(ModeChange::Activated, &ModeInfo::from(mode));
+
+    if let Some(ref on_enter) = mode.on_enter {
+        run_action(
+            on_enter,
+            state.key_delay,
+            &*state.clock,
+            &mut state.cycles,
+        )?;
+    }
 
     Ok(())
 }
 
-fn run_action(map_action: &MapAction) -> Result<()> {
+fn deactivate_mode(
+    state: &mut State,
+    mode: &Mode,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    state.in_mode = None;
+    state.last_mode_activity = None;
+
+    on_mode_change(ModeChange::Deactivated, &ModeInfo::from(mode));
+
+    if let Some(ref on_exit) = mode.on_exit {
+        run_action(
+            on_exit,
+            state.key_delay,
+            &*state.clock,
+            &mut state.cycles,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Typing speed for `text` maps that pauses for `key_delay` milliseconds
+/// between characters. `autopilot` counts five characters to a word.
+fn words_per_minute(key_delay: Milliseconds) -> f64 {
+    if key_delay == 0 {
+        return f64::INFINITY;
+    }
+
+    60_000.0 / (key_delay as f64 * 5.0)
+}
+
+/// Keys pressed with `<down:K>` in an action. Any that are still held when
+/// the action ends, including by returning early or panicking, are released.
+struct HeldKeys<'a>(Vec<&'a KeyboardKeyWithModifiers>);
+
+impl<'a> HeldKeys<'a> {
+    /// Hold `key` if it's a `<down:K>` key, or let go of the key it releases
+    /// if it's an `<up:K>` key.
+    fn update(&mut self, key: &'a KeyboardKeyWithModifiers) {
+        if key.is_key_down() {
+            self.0.push(key);
+        } else {
+            self.0.retain(|down| !key.releases(down));
+        }
+    }
+
+    /// Flags of the modifiers being held.
+    fn flags(&self) -> Vec<Flag> {
+        self.0.iter().filter_map(|key| key.held_flag()).collect()
+    }
+}
+
+impl<'a> Drop for HeldKeys<'a> {
+    fn drop(&mut self) {
+        for key in self.0.drain(..).rev() {
+            key.release();
+        }
+    }
+}
+
+/// Run `map_action`, pausing for `key_delay` milliseconds between keys with
+/// `clock`. If it contains mode keys, return the last mode change they
+/// request, to be applied after the action has finished. `cycles` holds the
+/// positions of `toggle` and `cycle` mappings.
+fn run_action<'a>(
+    map_action: &'a MapAction,
+    key_delay: Milliseconds,
+    clock: &dyn Clock,
+    cycles: &mut Cycles,
+) -> Result<Option<ModeSwitch<'a>>> {
     match map_action.kind {
         MapKind::Map => {
             if let Action::Map(action) = &map_action.action {
-                for key in action {
-                    key.tap()
-                }
+                let mut held_keys = HeldKeys(Vec::new());
+
+                return Ok(
+                    run_keys(action, key_delay, clock, &mut held_keys)
+                );
             }
         },
         MapKind::Command => {
             if let Action::String(action) = &map_action.action {
-                let shell = match env::var_os("SHELL") {
-                    Some(s) => s,
-                    None => OsString::from("/bin/sh"),
-                };
-
-                return Command::new(shell)
-                    .arg("-c")
-                    .arg(action)
+                return shell_command(action)
                     .spawn()
-                    .map(|_| ())
+                    .map(|_| None)
                     .chain_err(|| "command failed to start");
             }
         },
+        MapKind::Text => {
+            if let Action::String(text) = &map_action.action {
+                type_text(text, key_delay);
+            }
+        },
+        MapKind::Do => {
+            if let Action::Steps { steps, stop_on_error, timeout } =
+                &map_action.action
+            {
+                return run_steps(
+                    steps,
+                    *stop_on_error,
+                    *timeout,
+                    key_delay,
+                    clock,
+                );
+            }
+        },
+        MapKind::If => {
+            if let Action::Conditional {
+                condition,
+                timeout,
+                then,
+                otherwise,
+            } = &map_action.action {
+                let branch = if run_condition(condition, *timeout) {
+                    Some(then)
+                } else {
+                    otherwise.as_ref()
+                };
+
+                return match branch {
+                    Some(branch) => {
+                        run_action(branch, key_delay, clock, cycles)
+                    },
+                    None => Ok(None),
+                };
+            }
+        },
+        MapKind::Cycle => {
+            if let Action::Cycle { key, actions, persist } =
+                &map_action.action
+            {
+                let index = cycles.advance(key, actions.len());
+
+                if *persist {
+                    if let Err(e) = cycles.save(key) {
+                        error!("{}", e);
+                    }
+                }
+
+                return run_steps(
+                    &actions[index],
+                    false,
+                    COMMAND_TIMEOUT,
+                    key_delay,
+                    clock,
+                );
+            }
+        },
     };
 
-    Ok(())
+    Ok(None)
+}
+
+/// Run the steps of a `do` or `if` block in order. Commands run to
+/// completion before the next step, or are stopped after `timeout`
+/// milliseconds. With `stop_on_error`, a command that fails stops the
+/// remaining steps with an error.
+fn run_steps<'a>(
+    steps: &'a [Step],
+    stop_on_error: bool,
+    timeout: Milliseconds,
+    key_delay: Milliseconds,
+    clock: &dyn Clock,
+) -> Result<Option<ModeSwitch<'a>>> {
+    let mut mode_switch = None;
+    let mut held_keys = HeldKeys(Vec::new());
+
+    for step in steps {
+        match step {
+            Step::Keys(keys) => {
+                let switch = run_keys(keys, key_delay, clock, &mut held_keys);
+
+                if switch.is_some() {
+                    mode_switch = switch;
+                }
+            },
+            Step::Text(text) => type_text(text, key_delay),
+            Step::Command(command) => {
+                if let Err(e) = run_command(command, timeout) {
+                    if stop_on_error {
+                        return Err(e);
+                    }
+
+                    error!("{}", e);
+                }
+            },
+            Step::Wait(ms) => clock.sleep(Duration::from_millis(*ms as u64)),
+        }
+    }
+
+    Ok(mode_switch)
+}
+
+/// Run a block's `command`, failing if it doesn't exit successfully within
+/// `timeout` milliseconds. A command that's still running is killed.
+fn run_command(command: &str, timeout: Milliseconds) -> Result<()> {
+    let mut child = group_command(command)
+        .spawn()
+        .chain_err(|| format!("command '{}' failed to start", command))?;
+
+    let status = wait_timeout(&mut child, timeout)
+        .chain_err(|| format!("command '{}' failed", command))?;
+
+    match status {
+        Some(ref status) if status.success() => Ok(()),
+        Some(status) => bail!("command '{}' failed with {}", command, status),
+        None => bail!("command '{}' timed out", command),
+    }
+}
+
+/// Run the `if` condition `command`, returning whether it exits successfully
+/// within `timeout` milliseconds. A command that's still running is killed.
+fn run_condition(command: &str, timeout: Milliseconds) -> bool {
+    let mut child = match group_command(command).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Condition '{}' failed to start: {}", command, e);
+
+            return false;
+        },
+    };
+
+    match wait_timeout(&mut child, timeout) {
+        Ok(Some(status)) => status.success(),
+        Ok(None) => {
+            error!("Condition '{}' timed out", command);
+
+            false
+        },
+        Err(e) => {
+            error!("Condition '{}' failed: {}", command, e);
+
+            false
+        },
+    }
+}
+
+/// Wait up to `timeout` milliseconds for `child` to exit. If it's still
+/// running, its process group is killed and `None` is returned.
+fn wait_timeout(
+    child: &mut Child,
+    timeout: Milliseconds,
+) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if Instant::now() >= deadline {
+            // Also stops anything the shell started
+            let group = child.id() as libc::pid_t;
+
+            if unsafe { libc::killpg(group, libc::SIGKILL) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            child.wait()?;
+
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Tap `keys`, pausing with `clock` for `key_delay` milliseconds between
+/// them and for `<wait:N>` keys. Keys
+/// pressed with `<down:K>` are added to `held_keys`. If there are mode keys,
+/// return the last mode change they request.
+fn run_keys<'a>(
+    keys: &'a [KeyboardKeyWithModifiers],
+    key_delay: Milliseconds,
+    clock: &dyn Clock,
+    held_keys: &mut HeldKeys<'a>,
+) -> Option<ModeSwitch<'a>> {
+    let mut mode_switch = None;
+
+    for (i, key) in keys.iter().enumerate() {
+        if i > 0 && key_delay > 0 {
+            clock.sleep(Duration::from_millis(key_delay as u64));
+        }
+
+        if let Some(ms) = key.wait() {
+            clock.sleep(Duration::from_millis(ms as u64));
+
+            continue;
+        }
+
+        held_keys.update(key);
+
+        match key.mode_switch() {
+            Some(switch) => mode_switch = Some(switch),
+            None => key.tap(&held_keys.flags()),
+        }
+    }
+
+    mode_switch
+}
+
+fn type_text(text: &str, key_delay: Milliseconds) {
+    autopilot::key::type_string(text, &[], words_per_minute(key_delay), 0.0);
+}
+
+/// A command that runs `command` with the user's shell.
+fn shell_command(command: &str) -> Command {
+    let shell = match env::var_os("SHELL") {
+        Some(s) => s,
+        None => OsString::from("/bin/sh"),
+    };
+
+    let mut shell = Command::new(shell);
+    shell.arg("-c").arg(command);
+
+    shell
+}
+
+/// A command that runs `command` with the user's shell in a new process
+/// group, so that it can be killed along with any processes it starts.
+fn group_command(command: &str) -> Command {
+    let mut shell = shell_command(command);
+
+    unsafe {
+        shell.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+
+    shell
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::ffi::{CStr, CString};
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use clock::SystemClock;
+    use ffi::dome_key_state_active_mode;
+    use super::*;
+
+    struct TestClock(Rc<Cell<Instant>>);
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    fn state_in_mode(mappings: &str, now: &Rc<Cell<Instant>>) -> State {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(mappings).unwrap()));
+        state.clock = Box::new(TestClock(now.clone()));
+        state.in_mode = Some(vec![HeadphoneButton::Play]);
+        state.last_mode_activity = Some(now.get());
+
+        state
+    }
+
+    extern "C" fn ignore_mode_change(
+        _mode_change: ModeChange,
+        _mode: *const ModeInfo,
+    ) {}
+
+    static TIMEOUT_DEACTIVATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_timeout_deactivations(
+        mode_change: ModeChange,
+        _mode: *const ModeInfo,
+    ) {
+        if let ModeChange::Deactivated = mode_change {
+            TIMEOUT_DEACTIVATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn check_mode_timeout_deactivates_mode_after_mode_timeout() {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut state = state_in_mode(
+            "mode <play> timeout=1000 {
+    map <up> <Nop>
+}
+",
+            &now,
+        );
+
+        now.set(now.get() + Duration::from_millis(999));
+        check_mode_timeout(&mut state, count_timeout_deactivations).unwrap();
+
+        assert_eq!(state.in_mode, Some(vec![HeadphoneButton::Play]));
+        assert_eq!(TIMEOUT_DEACTIVATIONS.load(Ordering::SeqCst), 0);
+
+        now.set(now.get() + Duration::from_millis(1));
+        check_mode_timeout(&mut state, count_timeout_deactivations).unwrap();
+
+        assert_eq!(state.in_mode, None);
+        assert_eq!(TIMEOUT_DEACTIVATIONS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn check_mode_timeout_uses_global_timeout_by_default() {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut state = state_in_mode(
+            "mode <play> {
+    map <up> <Nop>
+}
+",
+            &now,
+        );
+        state.mode_timeout = 200;
+
+        now.set(now.get() + Duration::from_millis(200));
+        check_mode_timeout(&mut state, ignore_mode_change).unwrap();
+
+        assert_eq!(state.in_mode, None);
+    }
+
+    #[test]
+    fn check_mode_timeout_of_0_overrides_global_timeout() {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut state = state_in_mode(
+            "mode <play> timeout=0 {
+    map <up> <Nop>
+}
+",
+            &now,
+        );
+        state.mode_timeout = 200;
+
+        now.set(now.get() + Duration::from_secs(60));
+        check_mode_timeout(&mut state, ignore_mode_change).unwrap();
+
+        assert_eq!(state.in_mode, Some(vec![HeadphoneButton::Play]));
+    }
+
+    static ONESHOT_DEACTIVATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_oneshot_deactivations(
+        mode_change: ModeChange,
+        _mode: *const ModeInfo,
+    ) {
+        if let ModeChange::Deactivated = mode_change {
+            ONESHOT_DEACTIVATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn run_key_action_deactivates_oneshot_mode_after_a_mapping() {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut state = state_in_mode(
+            "mode <play> oneshot {
+    map <up> <Nop>
+}
+",
+            &now,
+        );
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Down],
+            count_oneshot_deactivations,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, Some(vec![HeadphoneButton::Play]));
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up],
+            count_oneshot_deactivations,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, None);
+        assert_eq!(ONESHOT_DEACTIVATIONS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn run_key_action_deactivates_oneshot_mode_after_a_fallthrough_mapping() {
+        let now = Rc::new(Cell::new(Instant::now()));
+        let mut state = state_in_mode(
+            "map <down> <Nop>
+
+mode <play> oneshot fallthrough {
+    map <up> <Nop>
+}
+",
+            &now,
+        );
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Down],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, None);
+    }
+
+    #[test]
+    fn fallthrough_map_gets_global_mapping_not_in_mode() {
+        let map_group = MapGroup::parse(
+            "map <play> <Nop>
+
+mode <up> fallthrough {
+    map <up> <Nop>
+}
+"
+        ).unwrap();
+        let mode = &map_group.modes[&vec![HeadphoneButton::Up]];
+
+        assert_eq!(
+            fallthrough_map(&map_group, mode, None, &[HeadphoneButton::Play]),
+            map_group.maps.get(&vec![HeadphoneButton::Play]),
+        );
+        assert_eq!(
+            fallthrough_map(&map_group, mode, None, &[HeadphoneButton::Down]),
+            MapGroup::default().maps.get(&vec![HeadphoneButton::Down]),
+        );
+    }
+
+    #[test]
+    fn fallthrough_map_without_fallthrough_is_none() {
+        let map_group = MapGroup::parse(
+            "map <play> <Nop>
+
+mode <up> {
+    map <up> <Nop>
+}
+"
+        ).unwrap();
+        let mode = &map_group.modes[&vec![HeadphoneButton::Up]];
+
+        assert_eq!(
+            fallthrough_map(&map_group, mode, None, &[HeadphoneButton::Play]),
+            None,
+        );
+    }
+
+    thread_local! {
+        static ACTIVATED_MODE_NAME: RefCell<Option<String>> = RefCell::new(None);
+    }
+
+    extern "C" fn store_activated_mode_name(
+        mode_change: ModeChange,
+        mode: *const ModeInfo,
+    ) {
+        if let ModeChange::Activated = mode_change {
+            let name = unsafe { CStr::from_ptr((*mode).name) };
+
+            ACTIVATED_MODE_NAME.with(|n| {
+                *n.borrow_mut() = Some(name.to_string_lossy().into_owned());
+            });
+        }
+    }
+
+    #[test]
+    fn run_key_action_passes_mode_name_on_activation() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "map <play> <Nop>
+
+mode \"Editing\" <play> {
+    map <up> <Nop>
+}
+"
+        ).unwrap()));
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Play],
+            store_activated_mode_name,
+        ).unwrap();
+
+        ACTIVATED_MODE_NAME.with(|n| {
+            assert_eq!(*n.borrow(), Some("Editing".to_owned()));
+        });
+    }
+
+    thread_local! {
+        static REENTRANT_STATE: Cell<*const State> = Cell::new(ptr::null());
+        static ACTIVE_MODE_NAME: RefCell<Option<String>> = RefCell::new(None);
+    }
+
+    extern "C" fn store_active_mode_name(
+        _mode_change: ModeChange,
+        _mode: *const ModeInfo,
+    ) {
+        let state = REENTRANT_STATE.with(|state| state.get());
+        let mode = dome_key_state_active_mode(state);
+
+        ACTIVE_MODE_NAME.with(|n| {
+            *n.borrow_mut() = if mode.name.is_null() {
+                None
+            } else {
+                let name = unsafe { CStr::from_ptr(mode.name) };
+
+                Some(name.to_string_lossy().into_owned())
+            };
+        });
+    }
+
+    #[test]
+    fn run_key_action_allows_getting_active_mode_from_on_mode_change() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "mode \"Editing\" <play> {
+    map <up> <Nop>
+}
+"
+        ).unwrap()));
+
+        REENTRANT_STATE.with(|s| s.set(&state));
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Play],
+            store_active_mode_name,
+        ).unwrap();
+
+        ACTIVE_MODE_NAME.with(|n| {
+            assert_eq!(*n.borrow(), Some("Editing".to_owned()));
+        });
+    }
+
+    #[test]
+    fn run_key_action_switches_to_named_mode() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "map <play> <Nop>
+
+mode <play> {
+    map <up> <mode:Editing>
+}
+
+mode \"Editing\" <down> {
+    map <up> <Nop>
+}
+"
+        ).unwrap()));
+        state.in_mode = Some(vec![HeadphoneButton::Play]);
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, Some(vec![HeadphoneButton::Down]));
+    }
+
+    #[test]
+    fn run_key_action_exits_mode() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "mode <play> {
+    map <up> <exitmode>
+}
+"
+        ).unwrap()));
+        state.in_mode = Some(vec![HeadphoneButton::Play]);
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, None);
+    }
+
+    #[test]
+    fn run_key_action_escape_leaves_mode() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "escape <down><down>
+
+mode <play> {
+    map <up> <Nop>
+    map <down> <Nop>
+}
+"
+        ).unwrap()));
+        state.in_mode = Some(vec![HeadphoneButton::Play]);
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Down, HeadphoneButton::Down],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, None);
+    }
+
+    #[test]
+    fn run_key_action_config_escape_overrides_mappings_escape() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "escape <up><up>
+
+mode <play> {
+    map <down><down> <Nop>
+}
+"
+        ).unwrap()));
+        state.in_mode = Some(vec![HeadphoneButton::Play]);
+        state.escape = Some(vec![HeadphoneButton::Down, HeadphoneButton::Down]);
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Down, HeadphoneButton::Down],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(state.in_mode, None);
+    }
+
+    fn conflicting_map_group(conflict: &str) -> MapGroup {
+        MapGroup::parse(
+            &format!(
+                "conflict = {}
+map <up><up> <mode:Other>
+
+mode <up><up> {{
+    map <down> <Nop>
+}}
+
+mode \"Other\" <down><down> {{
+    map <up> <Nop>
+}}
+",
+                conflict,
+            )
+        ).unwrap()
+    }
+
+    #[test]
+    fn run_key_action_with_map_first_conflict_runs_map_and_mode() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(conflicting_map_group("map-first")));
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up, HeadphoneButton::Up],
+            ignore_mode_change,
+        ).unwrap();
+
+        // The map's mode switch is applied after the mode is activated
+        assert_eq!(
+            state.in_mode,
+            Some(vec![HeadphoneButton::Down, HeadphoneButton::Down])
+        );
+    }
+
+    #[test]
+    fn run_key_action_with_mode_only_conflict_only_activates_mode() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(conflicting_map_group("mode-only")));
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up, HeadphoneButton::Up],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(
+            state.in_mode,
+            Some(vec![HeadphoneButton::Up, HeadphoneButton::Up])
+        );
+    }
+
+    fn device_map_group() -> MapGroup {
+        MapGroup::parse(
+            "map <up><up> <mode:Global>
+
+device \"Pedal\" {
+    map <up><up> <mode:Pedal>
+}
+
+mode \"Global\" <down><down> {
+    map <up> <Nop>
+}
+
+mode \"Pedal\" <play><play> {
+    map <up> <Nop>
+}
+"
+        ).unwrap()
+    }
+
+    #[test]
+    fn run_key_action_prefers_device_mappings() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(device_map_group()));
+        state.device = Some(CString::new("Pedal").unwrap());
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up, HeadphoneButton::Up],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(
+            state.in_mode,
+            Some(vec![HeadphoneButton::Play, HeadphoneButton::Play])
+        );
+    }
+
+    #[test]
+    fn run_key_action_falls_back_to_unscoped_mappings() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(device_map_group()));
+        state.device = Some(CString::new("AirPods").unwrap());
+
+        run_key_action(
+            &mut state,
+            &[HeadphoneButton::Up, HeadphoneButton::Up],
+            ignore_mode_change,
+        ).unwrap();
+
+        assert_eq!(
+            state.in_mode,
+            Some(vec![HeadphoneButton::Down, HeadphoneButton::Down])
+        );
+    }
+
+    #[test]
+    fn run_key_action_looks_up_mode_then_device_then_global_mappings() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(
+            "map <up> <mode:Global>
+map <down> <mode:Global>
+
+device \"Pedal\" {
+    map <up> <mode:Pedal>
+    map <down><down> <mode:Pedal>
+}
+
+mode \"Start\" <play> fallthrough {
+    map <down><down> <mode:Mode>
+}
+
+mode \"Global\" <play+up> {
+    map <up> <Nop>
+}
+
+mode \"Pedal\" <play+down> {
+    map <up> <Nop>
+}
+
+mode \"Mode\" <up+down> {
+    map <up> <Nop>
+}
+"
+        ).unwrap()));
+        state.device = Some(CString::new("Pedal").unwrap());
+
+        let lookups = [
+            (
+                vec![HeadphoneButton::Down, HeadphoneButton::Down],
+                HeadphoneButton::UpDown,
+            ),
+            (vec![HeadphoneButton::Up], HeadphoneButton::PlayDown),
+            (vec![HeadphoneButton::Down], HeadphoneButton::PlayUp),
+        ];
+
+        for &(ref trigger, ref mode) in lookups.iter() {
+            state.in_mode = Some(vec![HeadphoneButton::Play]);
+
+            run_key_action(&mut state, trigger, ignore_mode_change).unwrap();
+
+            assert_eq!(state.in_mode, Some(vec![mode.clone()]));
+        }
+    }
+
+    #[test]
+    fn run_action_waits() {
+        let map_group = MapGroup::parse("map <play> <Nop><wait:30><Nop>")
+            .unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+
+        let mut cycles = Cycles::default();
+
+        let start = Instant::now();
+        let now = Rc::new(Cell::new(start));
+        run_action(map, 0, &TestClock(now.clone()), &mut cycles).unwrap();
+
+        assert_eq!(now.get() - start, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn run_action_pauses_for_key_delay_between_keys() {
+        let map_group = MapGroup::parse("map <play> <Nop><Nop><Nop>")
+            .unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+
+        let mut cycles = Cycles::default();
+
+        let start = Instant::now();
+        let now = Rc::new(Cell::new(start));
+        run_action(map, 15, &TestClock(now.clone()), &mut cycles).unwrap();
+
+        assert_eq!(now.get() - start, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn run_action_runs_do_block_steps_in_order() {
+        let map_group = MapGroup::parse(
+            "do <play> { keys <Nop>; wait 30; cmd false; keys <mode:Next>; }
+            mode \"Next\" <up> {
+                map <down> <Nop>
+            }"
+        ).unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+
+        let mut cycles = Cycles::default();
+
+        let start = Instant::now();
+        let now = Rc::new(Cell::new(start));
+        let clock = TestClock(now.clone());
+        let mode_switch = run_action(map, 0, &clock, &mut cycles).unwrap();
+
+        assert_eq!(now.get() - start, Duration::from_millis(30));
+        assert_eq!(
+            mode_switch,
+            Some(ModeSwitch::Enter(&CString::new("Next").unwrap()))
+        );
+    }
+
+    #[test]
+    fn run_action_stops_do_block_on_failed_command() {
+        let map_group = MapGroup::parse(
+            "do <play> stoponerror { cmd false; wait 1000; }"
+        ).unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+
+        let mut cycles = Cycles::default();
+
+        let start = Instant::now();
+        let now = Rc::new(Cell::new(start));
+
+        assert!(
+            run_action(map, 0, &TestClock(now.clone()), &mut cycles).is_err()
+        );
+        assert_eq!(now.get(), start);
+    }
+
+    #[test]
+    fn run_action_stops_do_block_command_after_timeout() {
+        let map_group = MapGroup::parse(
+            "do <play> stoponerror timeout=50 { cmd sleep 5; }"
+        ).unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+
+        let mut cycles = Cycles::default();
+
+        let start = Instant::now();
+
+        assert!(run_action(map, 0, &SystemClock, &mut cycles).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_action_runs_if_branch_for_condition() {
+        let map_group = MapGroup::parse(
+            "if <play> `true` { keys <mode:Then>; } else { keys <exitmode>; }
+            if <down> `false` { keys <mode:Then>; } else { keys <exitmode>; }
+            if <up> `false` { keys <mode:Then>; }
+            mode \"Then\" <up><up> {
+                map <down> <Nop>
+            }"
+        ).unwrap();
+        let mut cycles = Cycles::default();
+        let mut run = |button| {
+            run_action(
+                &map_group.maps[&vec![button]],
+                0,
+                &SystemClock,
+                &mut cycles,
+            ).unwrap()
+        };
+
+        assert_eq!(
+            run(HeadphoneButton::Play),
+            Some(ModeSwitch::Enter(&CString::new("Then").unwrap()))
+        );
+        assert_eq!(run(HeadphoneButton::Down), Some(ModeSwitch::Exit));
+        assert_eq!(run(HeadphoneButton::Up), None);
+    }
+
+    #[test]
+    fn held_keys_add_held_modifier_flags_to_later_keys() {
+        let map_group = MapGroup::parse(
+            "map <play> <down:D><down:S>a<C-b><up:D>c<D-d>"
+        ).unwrap();
+        let keys = match map_group.maps[&vec![HeadphoneButton::Play]].action {
+            Action::Map(ref keys) => keys,
+            _ => panic!("not a map"),
+        };
+
+        let mut held_keys = HeldKeys(Vec::new());
+        let flags = keys
+            .iter()
+            .map(|key| {
+                held_keys.update(key);
+
+                key.flags_with(&held_keys.flags())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            flags,
+            vec![
+                vec![Flag::Meta],
+                vec![Flag::Meta, Flag::Shift],
+                vec![Flag::Meta, Flag::Shift],
+                vec![Flag::Control, Flag::Meta, Flag::Shift],
+                vec![Flag::Shift],
+                vec![Flag::Shift],
+                vec![Flag::Meta, Flag::Shift],
+            ]
+        );
+    }
+
+    #[test]
+    fn run_action_runs_next_cycle_action_each_time() {
+        let map_group = MapGroup::parse(
+            "cycle <play> {
+                keys <mode:Cycle>
+            } {
+                keys <exitmode>
+            } {
+                keys <Nop>
+            }
+            mode \"Cycle\" <up><up> {
+                map <down> <Nop>
+            }"
+        ).unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+        let mut cycles = Cycles::default();
+
+        let enter = CString::new("Cycle").unwrap();
+        let expected = [
+            Some(ModeSwitch::Enter(&enter)),
+            Some(ModeSwitch::Exit),
+            None,
+            Some(ModeSwitch::Enter(&enter)),
+        ];
+
+        for mode_switch in expected.iter() {
+            assert_eq!(
+                &run_action(map, 0, &SystemClock, &mut cycles).unwrap(),
+                mode_switch
+            );
+        }
+    }
+
+    #[test]
+    fn run_key_action_keeps_cycle_positions_in_state() {
+        extern "C" fn on_mode_change(_: ModeChange, _: *const ModeInfo) {}
+
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(
+            MapGroup::parse("toggle <play> { keys <Nop>; } { keys <Nop>; }")
+                .unwrap()
+        ));
+
+        let trigger = vec![HeadphoneButton::Play];
+
+        run_key_action(&mut state, &trigger, on_mode_change).unwrap();
+        assert_eq!(state.cycles.positions.get("0"), Some(&1));
+
+        run_key_action(&mut state, &trigger, on_mode_change).unwrap();
+        assert_eq!(state.cycles.positions.get("0"), Some(&0));
+    }
+
+    #[test]
+    fn run_condition_times_out() {
+        let start = Instant::now();
+
+        assert!(run_condition("true", 1000));
+        assert!(!run_condition("sleep 5", 50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn run_condition_kills_processes_started_by_the_condition() {
+        let path = env::temp_dir()
+            .join(format!("dome-key-condition-{}", process::id()));
+        let condition = format!(
+            "(sleep 0.2; touch '{}') & wait",
+            path.display(),
+        );
+
+        assert!(!run_condition(&condition, 50));
+
+        thread::sleep(Duration::from_millis(400));
+        assert!(!path.exists());
+    }
+
+    /// Wait up to a second for a command started by an action to create
+    /// `path`.
+    fn wait_for_file(path: &Path) -> bool {
+        for _ in 0..100 {
+            if path.exists() {
+                return true;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        false
+    }
+
+    #[test]
+    fn run_key_action_runs_mode_enter_and_exit_actions() {
+        let entered = env::temp_dir()
+            .join(format!("dome-key-entered-{}", process::id()));
+        let exited = env::temp_dir()
+            .join(format!("dome-key-exited-{}", process::id()));
+        let mappings = format!(
+            "mode <up><up> {{
+    map <down> <Nop>
+    on_enter cmd touch '{}'
+    on_exit cmd touch '{}'
+}}
+",
+            entered.display(),
+            exited.display(),
+        );
+        let trigger = vec![HeadphoneButton::Up, HeadphoneButton::Up];
+
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(&mappings).unwrap()));
+
+        run_key_action(&mut state, &trigger, ignore_mode_change).unwrap();
+
+        assert_eq!(state.in_mode, Some(trigger.clone()));
+        assert!(wait_for_file(&entered));
+        assert!(!exited.exists());
+
+        run_key_action(&mut state, &trigger, ignore_mode_change).unwrap();
+
+        assert_eq!(state.in_mode, None);
+        assert!(wait_for_file(&exited));
+
+        fs::remove_file(&entered).unwrap();
+        fs::remove_file(&exited).unwrap();
+    }
+
+    #[test]
+    fn run_action_runs_nested_if_branch() {
+        let map_group = MapGroup::parse(
+            "if <play> `false` { keys <mode:Other>; } \
+                else if `true` { keys <exitmode>; }
+            mode \"Other\" <up> {
+                map <down> <Nop>
+            }"
+        ).unwrap();
+        let map = &map_group.maps[&vec![HeadphoneButton::Play]];
+        let mut cycles = Cycles::default();
+
+        assert_eq!(
+            run_action(map, 0, &SystemClock, &mut cycles).unwrap(),
+            Some(ModeSwitch::Exit)
+        );
+    }
+
+    #[test]
+    fn words_per_minute_types_one_character_per_key_delay() {
+        assert_eq!(words_per_minute(0), f64::INFINITY);
+
+        // 100 ms per character is 600 characters, or 120 words, a minute
+        assert_eq!(words_per_minute(100), 120.0);
+    }
 }
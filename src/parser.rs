@@ -41,14 +41,21 @@
 // IN AN ACTION OF CONTRACT, NEGLIGENCE, OR OTHER TORTIOUS ACTION, ARISING
 // OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::fmt;
 
 use autopilot;
-use autopilot::key::Flag;
+use autopilot::key::{Flag, KeyCodeConvertible};
 use combine::*;
 use combine::easy::Errors as CombineErrors;
 use combine::parser::choice::or;
+use combine::error::StreamError;
 use combine::parser::char::{
+    alpha_num,
+    digit,
+    hex_digit,
     newline,
     space,
     string,
@@ -56,21 +63,179 @@ use combine::parser::char::{
     tab,
 };
 use combine::parser::repeat::take_until;
+use combine::error::{Consumed, Tracked};
+use combine::stream::{PointerOffset, StreamErrorFor};
 use combine::stream::state::{SourcePosition, State};
+use core_graphics::event::CGKeyCode;
 
 use autopilot_internal::cg_event_mask_for_flags;
-use key_code::{self, NXKey, dkess_press_key};
+use config::Milliseconds;
+use key_code::{self, MAX_KEY_CODE, MAX_NX_KEY, NXKey, dkess_press_key};
+use key_name::{self, KeyIndex, KeyKind};
 
-#[repr(C)]
+/// A button code. Codes 0 to 2 are the Play, Up, and Down headphone buttons,
+/// matching the values of the original C enum. Other codes up to
+/// `MAX_BUTTON_CODE` are generic buttons from remotes, pedals, or other
+/// devices. Long presses, double presses, and chords are marked with flags
+/// in the upper bits.
+pub type ButtonCode = u32;
+
+pub const MAX_BUTTON_CODE: ButtonCode = 0xFFFF;
+
+const LONG_FLAG: ButtonCode = 0x1_0000;
+const DOUBLE_FLAG: ButtonCode = 0x2_0000;
+
+/// Chords hold a bit for each of Play, Up, and Down in their lower bits.
+const CHORD_FLAG: ButtonCode = 0x4_0000;
+
+#[repr(transparent)]
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
-pub enum HeadphoneButton {
-    Play,
-    Up,
-    Down,
+pub struct HeadphoneButton(pub ButtonCode);
+
+#[allow(non_upper_case_globals)]
+impl HeadphoneButton {
+    pub const Play: HeadphoneButton = HeadphoneButton(0);
+    pub const Up: HeadphoneButton = HeadphoneButton(1);
+    pub const Down: HeadphoneButton = HeadphoneButton(2);
+    pub const PlayLong: HeadphoneButton = HeadphoneButton(LONG_FLAG);
+    pub const UpLong: HeadphoneButton = HeadphoneButton(LONG_FLAG | 1);
+    pub const DownLong: HeadphoneButton = HeadphoneButton(LONG_FLAG | 2);
+    pub const PlayDouble: HeadphoneButton = HeadphoneButton(DOUBLE_FLAG);
+    pub const UpDouble: HeadphoneButton = HeadphoneButton(DOUBLE_FLAG | 1);
+    pub const DownDouble: HeadphoneButton = HeadphoneButton(DOUBLE_FLAG | 2);
+    pub const PlayUp: HeadphoneButton = HeadphoneButton(CHORD_FLAG | 0b011);
+    pub const PlayDown: HeadphoneButton = HeadphoneButton(CHORD_FLAG | 0b101);
+    pub const UpDown: HeadphoneButton = HeadphoneButton(CHORD_FLAG | 0b110);
+    pub const PlayUpDown: HeadphoneButton = HeadphoneButton(CHORD_FLAG | 0b111);
+}
+
+impl HeadphoneButton {
+    /// The long press variant of the button, or `None` for chords.
+    pub fn long(&self) -> Option<HeadphoneButton> {
+        self.code().map(|code| HeadphoneButton(code | LONG_FLAG))
+    }
+
+    /// The double press variant of the button, or `None` for chords.
+    pub fn double(&self) -> Option<HeadphoneButton> {
+        self.code().map(|code| HeadphoneButton(code | DOUBLE_FLAG))
+    }
+
+    /// The chord of this button or chord pressed together with `other`.
+    /// `None` if either is a long or double press or a generic button, or if
+    /// they share a button.
+    pub fn chord(&self, other: &HeadphoneButton) -> Option<HeadphoneButton> {
+        match (self.chord_bits(), other.chord_bits()) {
+            (Some(a), Some(b)) if a & b == 0 =>
+                Some(HeadphoneButton::from_chord_bits(a | b)),
+            _ => None,
+        }
+    }
+
+    /// Whether `other` is one of the buttons pressed in this chord.
+    pub fn chord_contains(&self, other: &HeadphoneButton) -> bool {
+        match (self.chord_bits(), other.chord_bits()) {
+            (Some(a), Some(b)) => a & b == b,
+            _ => false,
+        }
+    }
+
+    /// The button's code without press flags, or `None` for chords.
+    fn code(&self) -> Option<ButtonCode> {
+        if self.0 & CHORD_FLAG != 0 {
+            None
+        } else {
+            Some(self.0 & MAX_BUTTON_CODE)
+        }
+    }
+
+    /// Play, Up, Down, and their chords as a set of bits, one for each
+    /// button.
+    fn chord_bits(&self) -> Option<ButtonCode> {
+        match self.0 {
+            0 | 1 | 2 => Some(1 << self.0),
+            code if code & CHORD_FLAG != 0 => Some(code & !CHORD_FLAG),
+            _ => None,
+        }
+    }
+
+    fn from_chord_bits(bits: ButtonCode) -> HeadphoneButton {
+        match bits {
+            0b001 => HeadphoneButton::Play,
+            0b010 => HeadphoneButton::Up,
+            0b100 => HeadphoneButton::Down,
+            bits => HeadphoneButton(CHORD_FLAG | bits),
+        }
+    }
 }
 type Trigger = Vec<HeadphoneButton>;
 
+/// Where something was written in a mappings file, so errors found after
+/// parsing can point at it. Unknown when the input doesn't track lines, and
+/// ignored when comparing what it's attached to.
+#[derive(Clone, Copy, Debug, Default)]
+struct Span(Option<SourcePosition>);
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Span) -> bool {
+        true
+    }
+}
+
+impl From<SourcePosition> for Span {
+    fn from(position: SourcePosition) -> Self {
+        Span(Some(position))
+    }
+}
+
+impl From<PointerOffset> for Span {
+    fn from(_: PointerOffset) -> Self {
+        Span(None)
+    }
+}
+
+/// An error in a definition that's only found once the whole file is
+/// parsed, like a trigger using an unknown alias.
 #[derive(Debug)]
+struct DefinitionError {
+    span: Span,
+    unexpected: Option<char>,
+    message: String,
+}
+
+impl DefinitionError {
+    fn new(span: Span, message: String) -> Self {
+        DefinitionError {
+            span: span,
+            unexpected: None,
+            message: message,
+        }
+    }
+}
+
+/// A button named in a trigger, like `play`, `button:4`, or an alias.
+#[derive(Clone, Debug, PartialEq)]
+enum ButtonName {
+    Button(HeadphoneButton),
+    Alias(String, Span),
+}
+
+/// A button in a trigger as it's written. Buttons named with an alias are
+/// resolved after parsing, once every `alias` directive is known.
+#[derive(Clone, Debug, PartialEq)]
+enum TriggerButton {
+    Button(HeadphoneButton),
+    Unresolved(Vec<ButtonName>, Option<PressKind>, Span),
+}
+
+type UnresolvedTrigger = Vec<TriggerButton>;
+
+/// Button codes by the names given to them with `alias`.
+type Aliases = HashMap<String, ButtonCode>;
+
+/// Each trigger of a map or mode in a file, in order, and where it's mapped.
+type TriggerSpans = Vec<(Trigger, Span)>;
+
+#[derive(Clone, Debug)]
 struct Character(autopilot::key::Character);
 
 impl PartialEq for Character {
@@ -87,7 +252,7 @@ impl Character {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct KeyCode(autopilot::key::Code);
 
 impl PartialEq for KeyCode {
@@ -104,15 +269,81 @@ impl KeyCode {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// A virtual key code given by number, like `<code:0x35>`, for keys that
+/// don't have a name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RawKeyCode(CGKeyCode);
+
+impl autopilot::key::KeyCodeConvertible for RawKeyCode {
+    fn code(&self) -> CGKeyCode {
+        self.0
+    }
+
+    fn character(&self) -> Option<char> {
+        None
+    }
+
+    fn flags(&self) -> &[Flag] {
+        &[]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 enum KeyboardKey {
     Character(Character),
     KeyCode(KeyCode),
+    RawKeyCode(RawKeyCode),
     NXKey(NXKey),
     Nop,
+
+    /// Activate the mode with this name, written at the span.
+    Mode(CString, Span),
+    ExitMode,
+    Wait(Milliseconds),
+    Down(Box<KeyboardKey>),
+    Up(Box<KeyboardKey>),
+    Click(autopilot::mouse::Button),
+
+    /// Scroll up by this many lines, or down if negative.
+    Scroll(i32),
+
+    /// Move the mouse pointer relative to where it is.
+    MoveBy(i32, i32),
+
+    /// Move the mouse pointer to a point on the screen.
+    MoveTo(u32, u32),
 }
 
-#[derive(Debug, PartialEq)]
+impl From<KeyKind> for KeyboardKey {
+    fn from(kind: KeyKind) -> Self {
+        match kind {
+            KeyKind::Code(code) => KeyboardKey::KeyCode(KeyCode::new(code)),
+            KeyKind::Raw(code) => KeyboardKey::RawKeyCode(RawKeyCode(code)),
+            KeyKind::NX(code) => KeyboardKey::NXKey(code),
+        }
+    }
+}
+
+impl KeyboardKey {
+    /// Press or release the key without the other half of a tap. Only
+    /// characters and key codes can be held.
+    fn toggle(&self, down: bool, flags: &[Flag]) {
+        match *self {
+            KeyboardKey::Character(ref c) => {
+                autopilot::key::toggle(&c.0, down, flags, 0)
+            },
+            KeyboardKey::KeyCode(ref k) => {
+                autopilot::key::toggle(&k.0, down, flags, 0)
+            },
+            KeyboardKey::RawKeyCode(ref k) => {
+                autopilot::key::toggle(k, down, flags, 0)
+            },
+            _ => (),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct KeyboardKeyWithModifiers {
     key: KeyboardKey,
     flags: Vec<Flag>,
@@ -126,30 +357,364 @@ impl KeyboardKeyWithModifiers {
         }
     }
 
-    pub fn tap(&self) {
+    /// Press and release the key, adding the flags of modifiers `held` with
+    /// `<down:K>` keys earlier in the action.
+    pub fn tap(&self, held: &[Flag]) {
+        let flags = self.flags_with(held);
+
         match self.key {
             KeyboardKey::Character(ref c) => {
-                autopilot::key::tap(c.0, &self.flags, 0)
+                autopilot::key::tap(c.0, &flags, 0)
             },
             KeyboardKey::KeyCode(ref k) => {
-                autopilot::key::tap(k.0, &self.flags, 0)
+                autopilot::key::tap(k.0, &flags, 0)
+            },
+            KeyboardKey::RawKeyCode(k) => {
+                autopilot::key::tap(k, &flags, 0)
             },
             KeyboardKey::NXKey(nx) => {
-                let flags = cg_event_mask_for_flags(&self.flags);
+                let flags = cg_event_mask_for_flags(&flags);
 
                 unsafe {
                     dkess_press_key(nx, flags);
                 }
             },
             KeyboardKey::Nop => (),
+
+            // Mode keys are handled by `run_key_action`
+            KeyboardKey::Mode(..) | KeyboardKey::ExitMode => (),
+
+            // Waits are handled by `run_action`
+            KeyboardKey::Wait(_) => (),
+
+            KeyboardKey::Down(ref key) => key.toggle(true, &flags),
+            KeyboardKey::Up(ref key) => key.toggle(false, &flags),
+
+            KeyboardKey::Click(button) => {
+                autopilot::mouse::click(button, None)
+            },
+            KeyboardKey::Scroll(lines) => {
+                let direction = if lines < 0 {
+                    autopilot::mouse::ScrollDirection::Down
+                } else {
+                    autopilot::mouse::ScrollDirection::Up
+                };
+
+                autopilot::mouse::scroll(direction, lines.abs() as u32)
+            },
+            KeyboardKey::MoveBy(x, y) => {
+                let location = autopilot::mouse::location();
+
+                move_mouse(
+                    location.x + f64::from(x),
+                    location.y + f64::from(y),
+                )
+            },
+            KeyboardKey::MoveTo(x, y) => {
+                move_mouse(f64::from(x), f64::from(y))
+            },
+        }
+    }
+
+    /// The key's own modifier flags followed by any in `held` it doesn't
+    /// already have.
+    pub fn flags_with(&self, held: &[Flag]) -> Vec<Flag> {
+        let mut flags = self.flags.clone();
+
+        for flag in held {
+            if !flags.contains(flag) {
+                flags.push(*flag);
+            }
+        }
+
+        flags
+    }
+
+    /// The flag of the modifier held by a `<down:D>`, `<down:A>`, `<down:C>`,
+    /// or `<down:S>` key.
+    pub fn held_flag(&self) -> Option<Flag> {
+        let code = match self.key {
+            KeyboardKey::Down(ref key) => match **key {
+                KeyboardKey::KeyCode(ref code) => (code.0).0,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        match code {
+            autopilot::key::KeyCode::Meta => Some(Flag::Meta),
+            autopilot::key::KeyCode::Alt => Some(Flag::Alt),
+            autopilot::key::KeyCode::Control => Some(Flag::Control),
+            autopilot::key::KeyCode::Shift => Some(Flag::Shift),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a `<down:K>` key, which stays held until a matching
+    /// `<up:K>`.
+    pub fn is_key_down(&self) -> bool {
+        match self.key {
+            KeyboardKey::Down(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this is an `<up:K>` key that releases the `<down:K>` key
+    /// `down`.
+    pub fn releases(&self, down: &KeyboardKeyWithModifiers) -> bool {
+        match (&self.key, &down.key) {
+            (&KeyboardKey::Up(ref up), &KeyboardKey::Down(ref down)) =>
+                up == down,
+            _ => false,
+        }
+    }
+
+    /// Release a key held with `<down:K>`.
+    pub fn release(&self) {
+        if let KeyboardKey::Down(ref key) = self.key {
+            key.toggle(false, &self.flags);
+        }
+    }
+
+    /// Get the delay requested by this key, if it's a `<wait:N>` key.
+    pub fn wait(&self) -> Option<Milliseconds> {
+        match self.key {
+            KeyboardKey::Wait(ms) => Some(ms),
+            _ => None,
+        }
+    }
+
+    /// Get the mode change requested by this key, if it's a mode key.
+    pub fn mode_switch(&self) -> Option<ModeSwitch> {
+        match self.key {
+            KeyboardKey::Mode(ref name, _) => Some(ModeSwitch::Enter(name)),
+            KeyboardKey::ExitMode => Some(ModeSwitch::Exit),
+            _ => None,
+        }
+    }
+}
+
+/// Move the mouse pointer to a point on the screen, logging an error if it's
+/// off screen.
+fn move_mouse(x: f64, y: f64) {
+    let point = autopilot::geometry::Point::new(x, y);
+
+    if let Err(e) = autopilot::mouse::move_to(point) {
+        error!("Can't move mouse to ({}, {}): {:?}", x, y, e);
+    }
+}
+
+/// Formats the key the way it's written in a map action, using its canonical
+/// name from the `key_name` registry.
+impl fmt::Display for KeyboardKeyWithModifiers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let KeyboardKey::Character(ref c) = self.key {
+            if self.flags.is_empty() && is_plain_character((c.0).0) {
+                return write!(f, "{}", (c.0).0);
+            }
+        }
+
+        write!(f, "<")?;
+
+        for flag in &self.flags {
+            let prefix = match *flag {
+                Flag::Meta => "D-",
+                Flag::Alt => "A-",
+                Flag::Control => "C-",
+                Flag::Shift => "S-",
+                Flag::Help => "H-",
+            };
+
+            write!(f, "{}", prefix)?;
+        }
+
+        write!(f, "{}>", self.key)
+    }
+}
+
+/// Formats the part of a special key between the angle brackets.
+impl fmt::Display for KeyboardKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyboardKey::Character(ref c) => {
+                let c = (c.0).0;
+
+                if is_plain_character(c) {
+                    write!(f, "{}", c)
+                } else {
+                    write!(f, "U+{:04X}", c as u32)
+                }
+            },
+            KeyboardKey::KeyCode(ref k) => {
+                match key_name::name(KeyKind::Code((k.0).0)) {
+                    Some(name) => write!(f, "{}", name),
+                    None => write!(f, "code:{:#X}", k.0.code()),
+                }
+            },
+            KeyboardKey::RawKeyCode(k) => {
+                match key_name::name(KeyKind::Raw(k.0)) {
+                    Some(name) => write!(f, "{}", name),
+                    None => write!(f, "code:{:#X}", k.0),
+                }
+            },
+            KeyboardKey::NXKey(nx) => {
+                match key_name::name(KeyKind::NX(nx)) {
+                    Some(name) => write!(f, "{}", name),
+                    None => write!(f, "nx:{}", nx),
+                }
+            },
+            KeyboardKey::Nop => write!(f, "Nop"),
+            KeyboardKey::Mode(ref name, _) => {
+                write!(f, "mode:{}", name.to_string_lossy())
+            },
+            KeyboardKey::ExitMode => write!(f, "exitmode"),
+            KeyboardKey::Wait(ms) => write!(f, "wait:{}", ms),
+            KeyboardKey::Down(ref key) => write!(f, "down:{}", key),
+            KeyboardKey::Up(ref key) => write!(f, "up:{}", key),
+            KeyboardKey::Click(button) => {
+                match button {
+                    autopilot::mouse::Button::Left => write!(f, "click"),
+                    autopilot::mouse::Button::Right => write!(f, "rightclick"),
+                    autopilot::mouse::Button::Middle => {
+                        write!(f, "middleclick")
+                    },
+                }
+            },
+            KeyboardKey::Scroll(lines) => write!(f, "scroll:{}", lines),
+            KeyboardKey::MoveBy(x, y) => {
+                write!(f, "move:{},{}", signed(x), signed(y))
+            },
+            KeyboardKey::MoveTo(x, y) => write!(f, "moveto:{},{}", x, y),
         }
     }
 }
 
+/// Format `n` with a `+` sign if it's positive, to show a relative offset.
+fn signed(n: i32) -> String {
+    if n > 0 {
+        format!("+{}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// Whether `c` can be written as itself in a map action.
+fn is_plain_character(c: char) -> bool {
+    !c.is_control() && c != '<' && c != '>' && c != '\\'
+}
+
+/// A mode change requested in a map action with `<mode:Name>` or
+/// `<exitmode>`.
+#[derive(Debug, PartialEq)]
+pub enum ModeSwitch<'a> {
+    Enter(&'a CStr),
+    Exit,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Action {
     String(String),
     Map(Vec<KeyboardKeyWithModifiers>),
+
+    /// Steps of a `do` block, run in order. With `stop_on_error`, a failing
+    /// command stops the remaining steps. Commands still running after
+    /// `timeout` milliseconds are stopped and count as failures.
+    Steps {
+        steps: Vec<Step>,
+        stop_on_error: bool,
+        timeout: Milliseconds,
+    },
+
+    /// Run `then` if `condition` is a command that exits successfully within
+    /// `timeout` milliseconds, otherwise `otherwise`.
+    Conditional {
+        condition: String,
+        timeout: Milliseconds,
+        then: Box<MapAction>,
+        otherwise: Option<Box<MapAction>>,
+    },
+
+    /// Each press runs the next of `actions`, starting over after the last.
+    /// With `persist`, the position is saved across restarts.
+    Cycle {
+        /// Identifies the cycle's position in `State`. Set from its trigger
+        /// and enclosing mode or device after parsing.
+        key: String,
+        actions: Vec<Vec<Step>>,
+        persist: bool,
+    },
+}
+
+impl Action {
+    /// All keys in the action, including those in the steps of blocks.
+    fn keys(&self) -> Vec<&KeyboardKeyWithModifiers> {
+        match *self {
+            Action::String(_) => vec![],
+            Action::Map(ref keys) => keys.iter().collect(),
+            Action::Steps { ref steps, .. } => step_keys(steps),
+            Action::Conditional { ref then, ref otherwise, .. } => {
+                let mut keys = then.action.keys();
+
+                if let Some(ref otherwise) = *otherwise {
+                    keys.extend(otherwise.action.keys());
+                }
+
+                keys
+            },
+            Action::Cycle { ref actions, .. } => {
+                actions
+                    .iter()
+                    .flat_map(|steps| step_keys(steps))
+                    .collect()
+            },
+        }
+    }
+
+    /// Keys of the `persist` cycles in the action, including those in `if`
+    /// branches.
+    fn persistent_cycles(&self) -> Vec<&str> {
+        match *self {
+            Action::Cycle { ref key, persist: true, .. } => vec![key.as_str()],
+            Action::Conditional { ref then, ref otherwise, .. } => {
+                let mut keys = then.action.persistent_cycles();
+
+                if let Some(ref otherwise) = *otherwise {
+                    keys.extend(otherwise.action.persistent_cycles());
+                }
+
+                keys
+            },
+            _ => vec![],
+        }
+    }
+}
+
+/// The keys in the `keys` steps of a block.
+fn step_keys(steps: &[Step]) -> Vec<&KeyboardKeyWithModifiers> {
+    steps
+        .iter()
+        .flat_map(|step| match *step {
+            Step::Keys(ref keys) => keys.iter().collect(),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// How long an `if` condition can run before it's stopped and counted as
+/// false, unless the `if` sets its own `timeout`.
+pub const CONDITION_TIMEOUT: Milliseconds = 1000;
+
+/// How long a command in a block can run before it's stopped, unless a `do`
+/// block sets its own `timeout`.
+pub const COMMAND_TIMEOUT: Milliseconds = 5000;
+
+/// One step of a `do` block.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Keys(Vec<KeyboardKeyWithModifiers>),
+    Text(String),
+    Command(String),
+    Wait(Milliseconds),
 }
 
 #[repr(C)]
@@ -157,39 +722,180 @@ pub enum Action {
 pub enum MapKind {
     Map,
     Command,
+
+    /// Type the action as a string of Unicode text.
+    Text,
+
+    /// Run the steps of a `do` block.
+    Do,
+
+    /// Run the action of one branch of an `if`.
+    If,
+
+    /// Run the next steps of a `toggle` or `cycle`.
+    Cycle,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct MapAction {
     pub action: Action,
     pub kind: MapKind,
+
+    /// Run the action repeatedly at this interval while the trigger button
+    /// is held down. A value of 0 uses the global `repeat_interval`.
+    pub repeat: Option<Milliseconds>,
 }
 
 #[derive(Debug, PartialEq)]
 struct Map {
-    trigger: Trigger,
+    span: Span,
+    trigger: UnresolvedTrigger,
     action: Action,
     kind: MapKind,
+    repeat: Option<Milliseconds>,
+}
+
+impl Map {
+    fn resolve(
+        self,
+        aliases: &Aliases,
+    ) -> Result<(Trigger, MapAction), DefinitionError> {
+        Ok((
+            resolve_trigger(self.trigger, aliases)?,
+            MapAction {
+                action: self.action,
+                kind: self.kind,
+                repeat: self.repeat,
+            },
+        ))
+    }
 }
 
 type MapCollection = HashMap<Trigger, MapAction>;
 
+#[derive(Debug, Default, PartialEq)]
+pub struct ModeOptions {
+    /// Leave the mode after this many milliseconds without a button press.
+    /// Overrides the global `mode_timeout` setting. A value of 0 disables
+    /// the timeout.
+    pub timeout: Option<Milliseconds>,
+
+    /// Leave the mode after running any of its mappings.
+    pub oneshot: bool,
+
+    /// Use global mappings for triggers not mapped in the mode.
+    pub fallthrough: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Mode {
+    pub trigger: Trigger,
+    pub name: Option<CString>,
+    pub maps: MapCollection,
+    pub on_enter: Option<MapAction>,
+    pub on_exit: Option<MapAction>,
+    pub options: ModeOptions,
+}
+
+/// A mode as it's written, before the aliases in its triggers are resolved.
 #[derive(Debug, PartialEq)]
-struct Mode {
-    trigger: Trigger,
-    maps: MapCollection,
+struct ModeDefinition {
+    span: Span,
+    trigger: UnresolvedTrigger,
+    name: Option<CString>,
+    maps: Vec<Map>,
+    on_enter: Option<MapAction>,
+    on_exit: Option<MapAction>,
+    options: ModeOptions,
+}
+
+impl ModeDefinition {
+    fn resolve(
+        self,
+        aliases: &Aliases,
+        spans: &mut TriggerSpans,
+    ) -> Result<Mode, DefinitionError> {
+        Ok(Mode {
+            trigger: resolve_trigger(self.trigger, aliases)?,
+            name: self.name,
+            maps: resolve_maps(self.maps, aliases, spans)?,
+            on_enter: self.on_enter,
+            on_exit: self.on_exit,
+            options: self.options,
+        })
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct MapGroup {
     pub maps: MapCollection,
-    pub modes: HashMap<Trigger, MapCollection>,
+    pub modes: HashMap<Trigger, Mode>,
+
+    /// Leaves any active mode. Can't be used by a mapping.
+    pub escape: Option<Trigger>,
+
+    pub conflict: ConflictPolicy,
+
+    /// Mappings that only apply to triggers from the named device. They
+    /// take precedence over unscoped mappings.
+    pub devices: HashMap<CString, MapCollection>,
+}
+
+/// What to do when a trigger is both a global map and a mode trigger.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Run the map, then activate the mode.
+    MapFirst,
+
+    /// Only activate the mode.
+    ModeOnly,
+
+    /// Refuse to load mappings where a trigger is used by both. Modes replace
+    /// default mappings on the same trigger.
+    Error,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::MapFirst
+    }
 }
 
 #[derive(Debug, PartialEq)]
 enum Definition {
     Map(Map),
-    Mode(Mode),
+    Mode(ModeDefinition),
+    Escape(UnresolvedTrigger, Span),
+    Conflict(ConflictPolicy),
+    Alias(String, ButtonCode),
+    Device(CString, Vec<Map>),
+}
+
+/// Suffix of a headphone button like `<play:long>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PressKind {
+    Long,
+    Double,
+}
+
+#[derive(Debug, PartialEq)]
+enum ModeHook {
+    Enter(MapAction),
+    Exit(MapAction),
+}
+
+/// A hook or mapping in the body of a mode, which can come in any order.
+#[derive(Debug, PartialEq)]
+enum ModeItem {
+    Hook(ModeHook),
+    Map(Map),
+}
+
+#[derive(Debug, PartialEq)]
+enum ModeAttribute {
+    Timeout(Milliseconds),
+    Oneshot,
+    Fallthrough,
 }
 
 impl MapGroup {
@@ -199,38 +905,205 @@ impl MapGroup {
         let input = State::new(mappings);
         map_group().easy_parse(input).map(|t| t.0)
     }
-}
 
-/// Default headphone button mappings:
-///
-/// * Up → Volume up
-/// * Middle → Play
-/// * Down → Volume down
-impl Default for MapGroup {
-    fn default() -> Self {
-        let mut default_maps: MapCollection = HashMap::new();
-        default_maps.insert(
-            vec![HeadphoneButton::Up],
-            MapAction {
-                action: Action::Map(
-                    vec![KeyboardKeyWithModifiers::new(
-                        KeyboardKey::NXKey(key_code::NX_KEYTYPE_SOUND_UP),
-                        vec![],
-                    )]
-                ),
-                kind: MapKind::Map,
-            },
-        );
-        default_maps.insert(
-            vec![HeadphoneButton::Play],
-            MapAction {
-                action: Action::Map(
-                    vec![KeyboardKeyWithModifiers::new(
+    /// Get the global mapping for `trigger`, preferring one scoped to
+    /// `device`.
+    pub fn global_map(
+        &self,
+        device: Option<&CStr>,
+        trigger: &[HeadphoneButton],
+    ) -> Option<&MapAction> {
+        device
+            .and_then(|device| self.devices.get(device))
+            .and_then(|maps| maps.get(trigger))
+            .or_else(|| self.maps.get(trigger))
+    }
+
+    /// Whether any trigger in the group uses `button`.
+    pub fn uses_button(&self, button: &HeadphoneButton) -> bool {
+        let mode_triggers = self.modes
+            .values()
+            .flat_map(|mode| mode.maps.keys().chain(Some(&mode.trigger)));
+        let device_triggers = self.devices
+            .values()
+            .flat_map(|maps| maps.keys());
+
+        self.maps
+            .keys()
+            .chain(mode_triggers)
+            .chain(device_triggers)
+            .chain(self.escape.iter())
+            .any(|trigger| trigger.contains(button))
+    }
+
+    /// Whether a mapping or mode in the group uses `trigger`.
+    pub fn shadows(&self, trigger: &[HeadphoneButton]) -> bool {
+        self.maps.contains_key(trigger)
+            || self.modes.contains_key(trigger)
+            || self.modes
+                .values()
+                .any(|mode| mode.maps.contains_key(trigger))
+            || self.devices
+                .values()
+                .any(|maps| maps.contains_key(trigger))
+    }
+
+    /// Fail if `escape` can't be used as the escape trigger because a
+    /// mapping or mode in the group uses it. The default maps count, so
+    /// `<play>`, `<up>`, and `<down>` can't be escape triggers on their own.
+    pub fn check_escape(
+        &self,
+        escape: &[HeadphoneButton],
+    ) -> Result<(), &'static str> {
+        if self.shadows(escape) {
+            Err("escape trigger is shadowed by a mapping")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn mode_by_name(&self, name: &CStr) -> Option<&Mode> {
+        self.modes
+            .values()
+            .find(|mode| mode.name.as_ref().map_or(false, |n| &n[..] == name))
+    }
+
+    /// Keys of the `toggle` and `cycle` mappings with `persist`.
+    pub fn persistent_cycles(&self) -> Vec<&str> {
+        self.map_actions()
+            .into_iter()
+            .flat_map(|map_action| map_action.action.persistent_cycles())
+            .collect()
+    }
+
+    /// All actions in the group, including mode hooks.
+    fn map_actions(&self) -> Vec<&MapAction> {
+        let mode_actions = self.modes
+            .values()
+            .flat_map(|mode|
+                mode.maps.values()
+                    .chain(mode.on_enter.iter())
+                    .chain(mode.on_exit.iter())
+            );
+
+        let device_actions = self.devices
+            .values()
+            .flat_map(|maps| maps.values());
+
+        self.maps
+            .values()
+            .chain(mode_actions)
+            .chain(device_actions)
+            .collect()
+    }
+
+    /// Find the first `<mode:Name>` key in the file that doesn't refer to a
+    /// mode in the group, and where it's written.
+    fn undefined_mode_name(&self) -> Option<(&CStr, Span)> {
+        self.map_actions()
+            .into_iter()
+            .flat_map(|map_action| map_action.action.keys())
+            .filter_map(|key| match key.key {
+                KeyboardKey::Mode(ref name, span)
+                    if self.mode_by_name(name).is_none() =>
+                    Some((name.as_c_str(), span)),
+                _ => None,
+            })
+            .min_by_key(|&(_, span)| span.0)
+    }
+
+    /// Key each `toggle` and `cycle` by its trigger and enclosing mode or
+    /// device, so positions can be kept in `State` and saved across restarts.
+    fn key_cycles(&mut self) {
+        for (trigger, map_action) in self.maps.iter_mut() {
+            set_cycle_key(map_action, trigger_key(trigger));
+        }
+
+        for (mode_trigger, mode) in self.modes.iter_mut() {
+            for (trigger, map_action) in mode.maps.iter_mut() {
+                set_cycle_key(
+                    map_action,
+                    format!(
+                        "mode {} {}",
+                        trigger_key(mode_trigger),
+                        trigger_key(trigger),
+                    ),
+                );
+            }
+        }
+
+        for (device, maps) in self.devices.iter_mut() {
+            for (trigger, map_action) in maps.iter_mut() {
+                set_cycle_key(
+                    map_action,
+                    format!(
+                        "device \"{}\" {}",
+                        device.to_string_lossy(),
+                        trigger_key(trigger),
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Set the key of a cycle in `map_action`. Cycles in `if` branches are keyed
+/// by the branch they're in.
+fn set_cycle_key(map_action: &mut MapAction, cycle_key: String) {
+    match map_action.action {
+        Action::Cycle { ref mut key, .. } => *key = cycle_key,
+        Action::Conditional { ref mut then, ref mut otherwise, .. } => {
+            if let Some(ref mut otherwise) = *otherwise {
+                set_cycle_key(otherwise, format!("{} else", cycle_key));
+            }
+
+            set_cycle_key(then, format!("{} then", cycle_key));
+        },
+        _ => (),
+    }
+}
+
+/// A trigger's button codes, like `0-1-1`.
+fn trigger_key(trigger: &[HeadphoneButton]) -> String {
+    trigger
+        .iter()
+        .map(|button| button.0.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Default headphone button mappings:
+///
+/// * Up → Volume up
+/// * Middle → Play
+/// * Down → Volume down
+impl Default for MapGroup {
+    fn default() -> Self {
+        let mut default_maps: MapCollection = HashMap::new();
+        default_maps.insert(
+            vec![HeadphoneButton::Up],
+            MapAction {
+                action: Action::Map(
+                    vec![KeyboardKeyWithModifiers::new(
+                        KeyboardKey::NXKey(key_code::NX_KEYTYPE_SOUND_UP),
+                        vec![],
+                    )]
+                ),
+                kind: MapKind::Map,
+                repeat: None,
+            },
+        );
+        default_maps.insert(
+            vec![HeadphoneButton::Play],
+            MapAction {
+                action: Action::Map(
+                    vec![KeyboardKeyWithModifiers::new(
                         KeyboardKey::NXKey(key_code::NX_KEYTYPE_PLAY),
                         vec![],
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         default_maps.insert(
@@ -243,16 +1116,155 @@ impl Default for MapGroup {
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
 
         MapGroup {
             maps: default_maps,
             modes: HashMap::new(),
+            escape: None,
+            conflict: ConflictPolicy::default(),
+            devices: HashMap::new(),
         }
     }
 }
 
+/// Parse a trigger like `<play><up>` on its own, as used in the config file.
+/// Aliases can't be used.
+pub fn parse_trigger(
+    trigger_str: &str
+) -> Result<Trigger, CombineErrors<char, &str, SourcePosition>> {
+    let input = State::new(trigger_str);
+    (trigger_without_aliases(), eof()).easy_parse(input).map(|t| (t.0).0)
+}
+
+/// Resolve the aliases in `trigger` to button codes.
+fn resolve_trigger(
+    trigger: UnresolvedTrigger,
+    aliases: &Aliases,
+) -> Result<Trigger, DefinitionError> {
+    trigger
+        .into_iter()
+        .map(|button| match button {
+            TriggerButton::Button(button) => Ok(button),
+            TriggerButton::Unresolved(names, kind, span) => {
+                let buttons = names
+                    .into_iter()
+                    .map(|name| match name {
+                        ButtonName::Button(button) => Ok(button),
+                        ButtonName::Alias(alias, span) => aliases
+                            .get(&alias)
+                            .map(|&code| HeadphoneButton(code))
+                            .ok_or_else(|| DefinitionError {
+                                span: span,
+                                unexpected: alias.chars().next(),
+                                message: format!(
+                                    "unknown button alias '{}'",
+                                    alias,
+                                ),
+                            }),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                press_button(&buttons, kind)
+                    .map_err(|e| DefinitionError::new(span, e.to_owned()))
+            },
+        })
+        .collect()
+}
+
+/// Resolve the triggers of `maps`, adding where each is mapped to `spans`.
+/// A map replaces any earlier one with the same trigger.
+fn resolve_maps(
+    maps: Vec<Map>,
+    aliases: &Aliases,
+    spans: &mut TriggerSpans,
+) -> Result<MapCollection, DefinitionError> {
+    let mut collection = HashMap::new();
+
+    for map in maps {
+        let span = map.span;
+        let (trigger, map_action) = map.resolve(aliases)?;
+
+        spans.push((trigger.clone(), span));
+        collection.insert(trigger, map_action);
+    }
+
+    Ok(collection)
+}
+
+/// The button pressed as a chord of `buttons`, as a long or double press if
+/// `kind` is given.
+fn press_button(
+    buttons: &[HeadphoneButton],
+    kind: Option<PressKind>,
+) -> Result<HeadphoneButton, &'static str> {
+    let mut chord = buttons[0].clone();
+
+    for button in &buttons[1..] {
+        chord = match chord.chord(button) {
+            Some(chord) => chord,
+            None => return Err(
+                "chords can only combine different play, up, and down buttons"
+            ),
+        };
+    }
+
+    let button = match kind {
+        Some(PressKind::Long) => chord.long(),
+        Some(PressKind::Double) => chord.double(),
+        None => Some(chord),
+    };
+
+    button.ok_or("chords can't be long or double presses")
+}
+
+/// Parses `p` along with where it starts, for errors found after parsing.
+/// Unlike `(position(), p)`, this keeps what `p` expects in the errors of
+/// the parsers around it.
+struct Spanned<P>(P);
+
+impl<P> Parser for Spanned<P>
+where
+    P: Parser,
+    <P::Input as StreamOnce>::Position: Into<Span>,
+{
+    type Input = P::Input;
+    type Output = (Span, P::Output);
+    type PartialState = ();
+
+    fn parse_lazy(
+        &mut self,
+        input: &mut Self::Input,
+    ) -> ConsumedResult<Self::Output, Self::Input> {
+        let span = input.position().into();
+
+        self.0.parse_lazy(input).map(|output| (span, output))
+    }
+
+    fn add_error(
+        &mut self,
+        errors: &mut Tracked<<Self::Input as StreamOnce>::Error>,
+    ) {
+        self.0.add_error(errors)
+    }
+
+    fn add_consumed_expected_error(
+        &mut self,
+        errors: &mut Tracked<<Self::Input as StreamOnce>::Error>,
+    ) {
+        self.0.add_consumed_expected_error(errors)
+    }
+}
+
+fn spanned<P>(p: P) -> Spanned<P>
+where
+    P: Parser,
+    <P::Input as StreamOnce>::Position: Into<Span>,
+{
+    Spanned(p)
+}
 
 fn string_case_insensitive<I>(
     s: &'static str
@@ -280,33 +1292,151 @@ where
     try(string("cmd")).map(|_| MapKind::Command)
 }
 
-fn headphone_button<I>() -> impl Parser<Input = I, Output = HeadphoneButton>
+fn map_kind_text<I>() -> impl Parser<Input = I, Output = MapKind>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    try(string("text")).map(|_| MapKind::Text)
+}
+
+fn map_kind_do<I>() -> impl Parser<Input = I, Output = MapKind>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    try(string("do")).map(|_| MapKind::Do)
+}
+
+fn map_kind_if<I>() -> impl Parser<Input = I, Output = MapKind>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    try(string("if")).map(|_| MapKind::If)
+}
+
+fn is_builtin_button_name(name: &str) -> bool {
+    ["play", "up", "down", "button"]
+        .iter()
+        .any(|builtin| name.eq_ignore_ascii_case(builtin))
+}
+
+/// Parses a button name, which can be `play`, `up`, `down`, `button:N` for
+/// a generic button code, or an alias.
+fn button<I>() -> impl Parser<Input = I, Output = ButtonName>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(button_name()),
+        optional(try(token(':').with(many1::<String, _>(digit())))),
+    ).and_then(|((span, name), code): ((_, String), Option<String>)| {
+        let name = name.to_lowercase();
+
+        match (name.as_ref(), code) {
+            ("play", None) => Ok(ButtonName::Button(HeadphoneButton::Play)),
+            ("up", None) => Ok(ButtonName::Button(HeadphoneButton::Up)),
+            ("down", None) => Ok(ButtonName::Button(HeadphoneButton::Down)),
+            ("button", Some(code)) => match code.parse::<ButtonCode>() {
+                Ok(code) if code <= MAX_BUTTON_CODE =>
+                    Ok(ButtonName::Button(HeadphoneButton(code))),
+                _ => Err(
+                    StreamErrorFor::<I>::message_static_message(
+                        "button code too large"
+                    )
+                ),
+            },
+            ("button", None) => Err(
+                StreamErrorFor::<I>::message_static_message(
+                    "missing button code"
+                )
+            ),
+            (_, Some(_)) => Err(
+                StreamErrorFor::<I>::message_static_message(
+                    "only 'button' takes a button code"
+                )
+            ),
+            (name, None) => Ok(ButtonName::Alias(name.to_owned(), span)),
+        }
+    })
+}
+
+fn button_name<I>() -> impl Parser<Input = I, Output = String>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    many1(satisfy(|c: char| c.is_alphanumeric() || c == '-' || c == '_'))
+}
+
+fn headphone_button<I>() -> impl Parser<Input = I, Output = TriggerButton>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
 {
     between(
         token('<'),
         token('>'),
-        choice!(
-            string_cmp("play", |l, r| l.eq_ignore_ascii_case(&r))
-                .map(|_| HeadphoneButton::Play),
-            string_cmp("up", |l, r| l.eq_ignore_ascii_case(&r))
-                .map(|_| HeadphoneButton::Up),
-            string_cmp("down", |l, r| l.eq_ignore_ascii_case(&r))
-                .map(|_| HeadphoneButton::Down)
-        ),
+        (
+            spanned(sep_by1::<Vec<ButtonName>, _, _>(button(), token('+'))),
+            optional(
+                token(':').with(
+                    or(
+                        try(string_case_insensitive("long"))
+                            .map(|_| PressKind::Long),
+                        try(string_case_insensitive("double"))
+                            .map(|_| PressKind::Double),
+                    )
+                )
+            ),
+        ).and_then(|((span, names), kind): ((_, Vec<ButtonName>), _)| {
+            let mut buttons = Vec::new();
+
+            for name in &names {
+                match *name {
+                    ButtonName::Button(ref button) =>
+                        buttons.push(button.clone()),
+
+                    // Aliases are resolved once the whole file is parsed
+                    ButtonName::Alias(..) => return Ok(
+                        TriggerButton::Unresolved(names.clone(), kind, span)
+                    ),
+                }
+            }
+
+            press_button(&buttons, kind)
+                .map(|button| TriggerButton::Button(button))
+                .map_err(|e| StreamErrorFor::<I>::message_static_message(e))
+        }),
     )
 }
 
-fn trigger<I>() -> impl Parser<Input = I, Output = Trigger>
+fn trigger<I>() -> impl Parser<Input = I, Output = UnresolvedTrigger>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
 {
     many1(headphone_button())
 }
 
+/// Parses a trigger outside a mappings file, where there are no aliases.
+fn trigger_without_aliases<I>() -> impl Parser<Input = I, Output = Trigger>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    trigger().and_then(|trigger|
+        resolve_trigger(trigger, &Aliases::new())
+            .map_err(|e| StreamErrorFor::<I>::message_message(e.message))
+    )
+}
+
 fn action<I>() -> impl Parser<Input = I, Output = Action>
 where
     I: Stream<Item = char>,
@@ -320,21 +1450,24 @@ fn action_map<I>() -> impl Parser<Input = I, Output = Action>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
 {
     (
         many(
             choice!(
                 action_character()
                     .map(|c|
-                        KeyboardKeyWithModifiers::new(
+                        vec![KeyboardKeyWithModifiers::new(
                             KeyboardKey::Character(Character::new(c)),
                             vec![],
-                        )
+                        )]
                     ),
                 special_key()
             )
         ),
-    ).map(|(keys,)| Action::Map(keys))
+    ).map(|(keys,): (Vec<Vec<KeyboardKeyWithModifiers>>,)|
+        Action::Map(keys.into_iter().flat_map(|keys| keys).collect())
+    )
 }
 
 fn action_character<I>() -> impl Parser<Input = I, Output = char>
@@ -359,396 +1492,1838 @@ where
     )
 }
 
-fn special_key<I>() -> impl Parser<Input = I, Output = KeyboardKeyWithModifiers>
+/// The largest repeat count allowed in a special key like `<5*Down>`.
+const MAX_KEY_REPEAT: usize = 100;
+
+/// Parses a key in angle brackets. A count like `<5*Down>` repeats the key.
+/// Modifier prefixes are `D-` (Command), `A-` (Option), `C-` (Control), `S-`
+/// (Shift), `H-` (Help), and `Fn-`.
+fn special_key<I>()
+-> impl Parser<Input = I, Output = Vec<KeyboardKeyWithModifiers>>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
 {
     between(
         token('<'),
         token('>'),
-        choice((
-            try((
-                many(key_modifier()),
-                or(
-                    key_code().map(|code| KeyboardKey::KeyCode(code)),
-                    nx_key().map(|code| KeyboardKey::NXKey(code)),
-                ),
-            )),
-            try((
-                many1(key_modifier()),
-                action_character().map(|c|
-                    KeyboardKey::Character(Character::new(c))
-                ),
+        (
+            optional(key_repeat_count()),
+            choice((
+                // Before key codes so `down:` and `up:` aren't read as the
+                // arrow keys
+                try((value(vec![]), key_toggle())),
+                try((
+                    many(key_modifier()),
+                    choice!(
+                        raw_key_code(),
+                        raw_nx_key().map(|code| KeyboardKey::NXKey(code)),
+                        unicode_character().map(|c|
+                            KeyboardKey::Character(Character::new(c))
+                        ),
+                        named_key()
+                    ),
+                )),
+                try((
+                    many1(key_modifier()),
+                    action_character().map(|c|
+                        KeyboardKey::Character(Character::new(c))
+                    ),
+                )),
+                try((value(vec![]), mode_key())),
+                try((value(vec![]), wait_key())),
+                try((value(vec![]), mouse_key())),
+                try((value(vec![]), nop())),
             )),
-            try((value(vec![]), nop())),
-        ))
-    ).map(|(modifiers, key): (Vec<Flag>, KeyboardKey)| {
-        KeyboardKeyWithModifiers::new(
-            key,
-            modifiers,
         )
+    ).map(|(count, (modifiers, key)): (_, (Vec<Modifier>, KeyboardKey))| {
+        let flags = modifiers
+            .iter()
+            .filter_map(|modifier| match *modifier {
+                Modifier::Flag(flag) => Some(flag),
+                Modifier::Function => None,
+            })
+            .collect();
+
+        let mut keys = vec![
+            KeyboardKeyWithModifiers::new(
+                key,
+                flags,
+            );
+            count.unwrap_or(1)
+        ];
+
+        if modifiers.contains(&Modifier::Function) {
+            let function = KeyboardKey::RawKeyCode(
+                RawKeyCode(key_code::KVK_FUNCTION)
+            );
+
+            keys.insert(
+                0,
+                KeyboardKeyWithModifiers::new(
+                    KeyboardKey::Down(Box::new(function.clone())),
+                    vec![],
+                ),
+            );
+            keys.push(
+                KeyboardKeyWithModifiers::new(
+                    KeyboardKey::Up(Box::new(function)),
+                    vec![],
+                ),
+            );
+        }
+
+        keys
     })
 }
 
-fn key_modifier<I>() -> impl Parser<Input = I, Output = Flag>
+/// Parses the `5*` in `<5*Down>`.
+fn key_repeat_count<I>() -> impl Parser<Input = I, Output = usize>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    choice!(
-        try(string_case_insensitive("D-"))
-            .map(|_| Flag::Meta),
-        try(string_case_insensitive("A-"))
-            .map(|_| Flag::Alt),
-        try(string_case_insensitive("C-"))
-            .map(|_| Flag::Control),
-        try(string_case_insensitive("S-"))
-            .map(|_| Flag::Shift)
+    try((
+        many1::<String, _>(digit()),
+        token('*'),
+    )).and_then(|(count, _)|
+        match count.parse::<usize>() {
+            Ok(count) if count >= 1 && count <= MAX_KEY_REPEAT => Ok(count),
+            _ => Err(
+                StreamErrorFor::<I>::message_message(
+                    format!(
+                        "repeat count must be between 1 and {}",
+                        MAX_KEY_REPEAT,
+                    )
+                )
+            ),
+        }
     )
 }
 
-fn key_code<I>() -> impl Parser<Input = I, Output = KeyCode>
+/// Parses a decimal number, or a hexadecimal one prefixed with `0x`.
+fn number<I>() -> impl Parser<Input = I, Output = u32>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    choice!(
-        try(string_case_insensitive("F1"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F1)),
-        try(string_case_insensitive("F2"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F2)),
-        try(string_case_insensitive("F3"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F3)),
-        try(string_case_insensitive("F4"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F4)),
-        try(string_case_insensitive("F5"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F5)),
-        try(string_case_insensitive("F6"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F6)),
-        try(string_case_insensitive("F7"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F7)),
-        try(string_case_insensitive("F8"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F8)),
-        try(string_case_insensitive("F9"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F9)),
-        try(string_case_insensitive("F10"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F10)),
-        try(string_case_insensitive("F11"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F11)),
-        try(string_case_insensitive("F12"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::F12)),
-        try(string_case_insensitive("Left"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::LeftArrow)),
-        try(string_case_insensitive("Right"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::RightArrow)),
-        try(string_case_insensitive("Down"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::DownArrow)),
-        try(string_case_insensitive("Up"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::UpArrow)),
-        try(string_case_insensitive("Home"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Home)),
-        try(string_case_insensitive("End"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::End)),
-        try(string_case_insensitive("PageUp"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::PageUp)),
-        try(string_case_insensitive("PageDown"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::PageDown)),
-        try(string_case_insensitive("Return"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Return)),
-        try(string_case_insensitive("Enter"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Return)),
-        try(string_case_insensitive("CR"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Return)),
-        try(string_case_insensitive("Del"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Delete)),
-        try(string_case_insensitive("BS"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Backspace)),
-        try(string_case_insensitive("Esc"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Escape)),
-        try(string_case_insensitive("CapsLock"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::CapsLock)),
-        try(string_case_insensitive("Tab"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Tab)),
-        try(string_case_insensitive("Space"))
-            .map(|_| KeyCode::new(autopilot::key::KeyCode::Space))
+    or(
+        try(string_case_insensitive("0x"))
+            .with(many1(hex_digit()))
+            .and_then(|n: String|
+                u32::from_str_radix(&n, 16)
+                    .map_err(|_|
+                        StreamErrorFor::<I>::message_static_message(
+                            "number too large"
+                        )
+                    )
+            ),
+        many1(digit())
+            .and_then(|n: String|
+                n.parse::<u32>()
+                    .map_err(|_|
+                        StreamErrorFor::<I>::message_static_message(
+                            "number too large"
+                        )
+                    )
+            ),
     )
 }
 
-fn nx_key<I>() -> impl Parser<Input = I, Output = NXKey>
+/// Parses `code:N`, a virtual key code by number.
+fn raw_key_code<I>() -> impl Parser<Input = I, Output = KeyboardKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    choice!(
-        try(string_case_insensitive("VolumeUp"))
-            .map(|_| key_code::NX_KEYTYPE_SOUND_UP),
-        try(string_case_insensitive("VolumeDown"))
-            .map(|_| key_code::NX_KEYTYPE_SOUND_DOWN),
-        try(string_case_insensitive("Mute"))
-            .map(|_| key_code::NX_KEYTYPE_MUTE),
-        try(string_case_insensitive("BrightnessUp"))
-            .map(|_| key_code::NX_KEYTYPE_BRIGHTNESS_UP),
-        try(string_case_insensitive("BrightnessDown"))
-            .map(|_| key_code::NX_KEYTYPE_BRIGHTNESS_DOWN),
-        try(string_case_insensitive("Help"))
-            .map(|_| key_code::NX_KEYTYPE_HELP),
-        try(string_case_insensitive("Power"))
-            .map(|_| key_code::NX_POWER_KEY),
-        try(string_case_insensitive("NumLock"))
-            .map(|_| key_code::NX_KEYTYPE_NUM_LOCK),
-
-        try(string_case_insensitive("ContrastUp"))
-            .map(|_| key_code::NX_KEYTYPE_CONTRAST_UP),
-        try(string_case_insensitive("ContrastDown"))
-            .map(|_| key_code::NX_KEYTYPE_CONTRAST_DOWN),
-        try(string_case_insensitive("Eject"))
-            .map(|_| key_code::NX_KEYTYPE_EJECT),
-        try(string_case_insensitive("VidMirror"))
-            .map(|_| key_code::NX_KEYTYPE_VIDMIRROR),
-
-        try(string_case_insensitive("Play"))
-            .map(|_| key_code::NX_KEYTYPE_PLAY),
-        try(string_case_insensitive("Next"))
-            .map(|_| key_code::NX_KEYTYPE_NEXT),
-        try(string_case_insensitive("Previous"))
-            .map(|_| key_code::NX_KEYTYPE_PREVIOUS),
-        try(string_case_insensitive("Fast"))
-            .map(|_| key_code::NX_KEYTYPE_FAST),
-        try(string_case_insensitive("Rewind"))
-            .map(|_| key_code::NX_KEYTYPE_REWIND),
-
-        try(string_case_insensitive("IlluminationUp"))
-            .map(|_| key_code::NX_KEYTYPE_ILLUMINATION_UP),
-        try(string_case_insensitive("IlluminationDown"))
-            .map(|_| key_code::NX_KEYTYPE_ILLUMINATION_DOWN),
-        try(string_case_insensitive("IlluminationToggle"))
-            .map(|_| key_code::NX_KEYTYPE_ILLUMINATION_TOGGLE)
-    )
+    try(string_case_insensitive("code:"))
+        .with(number())
+        .and_then(|code|
+            if code <= MAX_KEY_CODE as u32 {
+                Ok(KeyboardKey::RawKeyCode(RawKeyCode(code as CGKeyCode)))
+            } else {
+                Err(
+                    StreamErrorFor::<I>::message_message(
+                        format!("key code must be at most {:#X}", MAX_KEY_CODE)
+                    )
+                )
+            }
+        )
 }
 
-fn nop<I>() -> impl Parser<Input = I, Output = KeyboardKey>
+/// Parses `nx:N`, an NX key type by number.
+fn raw_nx_key<I>() -> impl Parser<Input = I, Output = NXKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    string_case_insensitive("Nop")
-        .map(|_| KeyboardKey::Nop)
+    try(string_case_insensitive("nx:"))
+        .with(number())
+        .and_then(|code|
+            if code <= MAX_NX_KEY as u32 {
+                Ok(code as NXKey)
+            } else {
+                Err(
+                    StreamErrorFor::<I>::message_message(
+                        format!("NX key type must be at most {}", MAX_NX_KEY)
+                    )
+                )
+            }
+        )
 }
 
-fn whitespace_separator<I>() -> impl Parser<Input = I>
+/// Parses `U+XXXX`, a Unicode character by code point.
+fn unicode_character<I>() -> impl Parser<Input = I, Output = char>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    skip_many1(space().or(tab()))
+    try(string_case_insensitive("U+"))
+        .with(many1(hex_digit()))
+        .and_then(|code: String| {
+            let c = if code.len() <= 6 {
+                u32::from_str_radix(&code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+            } else {
+                None
+            };
+
+            c.ok_or_else(||
+                StreamErrorFor::<I>::message_static_message(
+                    "invalid Unicode code point"
+                )
+            )
+        })
 }
 
-fn map_map<I>() -> impl Parser<Input = I, Output = Map>
+/// A modifier prefix in a special key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Modifier {
+    Flag(Flag),
+
+    /// `Fn` has no event flag, so the Fn key is held down around the key
+    /// instead.
+    Function,
+}
+
+fn key_modifier<I>() -> impl Parser<Input = I, Output = Modifier>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    (
-        map_kind_map(),
-        whitespace_separator(),
-        trigger(),
-        whitespace_separator(),
-        action_map()
-    ).map(|(kind, _, trigger, _, action)|
-        Map {
-            trigger: trigger,
-            action: action,
-            kind: kind,
-        }
+    choice!(
+        try(string_case_insensitive("D-"))
+            .map(|_| Modifier::Flag(Flag::Meta)),
+        try(string_case_insensitive("A-"))
+            .map(|_| Modifier::Flag(Flag::Alt)),
+        try(string_case_insensitive("C-"))
+            .map(|_| Modifier::Flag(Flag::Control)),
+        try(string_case_insensitive("S-"))
+            .map(|_| Modifier::Flag(Flag::Shift)),
+        try(string_case_insensitive("H-"))
+            .map(|_| Modifier::Flag(Flag::Help)),
+        try(string_case_insensitive("Fn-"))
+            .map(|_| Modifier::Function)
     )
 }
 
-fn map_cmd<I>() -> impl Parser<Input = I, Output = Map>
+/// Parses a key name from the `key_name` registry, like `PageUp` or
+/// `VolumeUp`. `dome-key --list-keys` prints them all. The names are indexed
+/// once when the parser is built.
+fn named_key<I>() -> impl Parser<Input = I, Output = KeyboardKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    (
-        map_kind_cmd(),
-        whitespace_separator(),
-        trigger(),
-        whitespace_separator(),
-        action()
-    ).map(|(kind, _, trigger, _, action)|
-        Map {
-            trigger: trigger,
-            action: action,
-            kind: kind,
-        }
-    )
+    let index = KeyIndex::new();
+
+    many1(alpha_num())
+        .and_then(move |name: String|
+            index.lookup(&name)
+                .map(|kind| KeyboardKey::from(kind))
+                .ok_or_else(||
+                    StreamErrorFor::<I>::message_message(
+                        format!("unknown key name '{}'", name)
+                    )
+                )
+        )
 }
 
-fn map<I>() -> impl Parser<Input = I, Output = Map>
+/// Parses `mode:Name`, which activates the mode with the given name, or
+/// `exitmode`, which deactivates the current mode.
+fn mode_key<I>() -> impl Parser<Input = I, Output = KeyboardKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
 {
     or(
-        map_map(),
-        map_cmd(),
+        (
+            try(string_case_insensitive("mode:")),
+            spanned(many1(satisfy(|c| c != '>' && c != '\n'))),
+        ).and_then(|(_, (span, name)): (_, (_, String))|
+            CString::new(name)
+                .map(|name| KeyboardKey::Mode(name, span))
+                .map_err(|_|
+                    StreamErrorFor::<I>::message_static_message(
+                        "mode name contains a null character"
+                    )
+                )
+        ),
+        try(string_case_insensitive("exitmode"))
+            .map(|_| KeyboardKey::ExitMode),
     )
 }
 
-fn maps<I>() -> impl Parser<Input = I, Output = MapCollection>
+/// Parses `down:K` or `up:K`, which press or release `K` on its own so it
+/// can be held while other keys are tapped. `K` is a key name, one of the
+/// uppercase modifier letters `D`, `A`, `C`, or `S`, a `code:N` key code, a
+/// `U+XXXX` character, or a character.
+fn key_toggle<I>() -> impl Parser<Input = I, Output = KeyboardKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    many1::<Vec<Map>, _>(map().skip(blank()))
-       .map(|collection| {
-            let mut maps = HashMap::new();
-
-            for map in collection {
-                maps.insert(
-                    map.trigger,
-                    MapAction {
-                        action: map.action,
-                        kind: map.kind,
-                    }
-                );
-            }
+    let modifier_key = choice!(
+        token('D').map(|_| autopilot::key::KeyCode::Meta),
+        token('A').map(|_| autopilot::key::KeyCode::Alt),
+        token('C').map(|_| autopilot::key::KeyCode::Control),
+        token('S').map(|_| autopilot::key::KeyCode::Shift)
+    );
 
-            maps
-        })
+    (
+        or(
+            try(string_case_insensitive("down:")).map(|_| true),
+            try(string_case_insensitive("up:")).map(|_| false),
+        ),
+        choice!(
+            raw_key_code(),
+            try(unicode_character().skip(look_ahead(token('>'))))
+                .map(|c| KeyboardKey::Character(Character::new(c))),
+            try(named_key().skip(look_ahead(token('>')))),
+            try(modifier_key.skip(look_ahead(token('>'))))
+                .map(|code| KeyboardKey::KeyCode(KeyCode::new(code))),
+            action_character()
+                .map(|c| KeyboardKey::Character(Character::new(c)))
+        ),
+    ).map(|(down, key)|
+        if down {
+            KeyboardKey::Down(Box::new(key))
+        } else {
+            KeyboardKey::Up(Box::new(key))
+        }
+    )
 }
 
-fn map_collection<I>() -> impl Parser<Input = I, Output = MapCollection>
+/// Parses `wait:N`, which pauses for `N` milliseconds before the next key.
+fn wait_key<I>() -> impl Parser<Input = I, Output = KeyboardKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    (
-        blank(),
-        maps(),
-    ).map(|(_, collection)| collection)
+    try(string_case_insensitive("wait:"))
+        .with(milliseconds())
+        .map(|ms| KeyboardKey::Wait(ms))
 }
 
-fn mode<I>() -> impl Parser<Input = I, Output = Mode>
+/// Parses a mouse action: `click`, `rightclick`, `middleclick`, `scroll:N`
+/// (negative scrolls down), `move:X,Y` to move the pointer relative to where
+/// it is, or `moveto:X,Y` to move it to a point on the screen.
+fn mouse_key<I>() -> impl Parser<Input = I, Output = KeyboardKey>
 where
     I: Stream<Item = char>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    (
-        string("mode"),
-        whitespace_separator(),
-        trigger(),
-        whitespace_separator(),
+    choice!(
+        try(string_case_insensitive("click"))
+            .map(|_| KeyboardKey::Click(autopilot::mouse::Button::Left)),
+        try(string_case_insensitive("rightclick"))
+            .map(|_| KeyboardKey::Click(autopilot::mouse::Button::Right)),
+        try(string_case_insensitive("middleclick"))
+            .map(|_| KeyboardKey::Click(autopilot::mouse::Button::Middle)),
+        try(string_case_insensitive("scroll:"))
+            .with(signed_number())
+            .and_then(|lines|
+                // The number of lines is scrolled as its absolute value
+                match lines.checked_abs() {
+                    Some(_) => Ok(KeyboardKey::Scroll(lines)),
+                    None => Err(
+                        StreamErrorFor::<I>::message_static_message(
+                            "number too large"
+                        )
+                    ),
+                }
+            ),
+        try(string_case_insensitive("moveto:"))
+            .with((number(), token(','), number()))
+            .and_then(|(x, _, y)|
+                if x <= i32::max_value() as u32
+                    && y <= i32::max_value() as u32 {
+                    Ok(KeyboardKey::MoveTo(x, y))
+                } else {
+                    Err(
+                        StreamErrorFor::<I>::message_static_message(
+                            "number too large"
+                        )
+                    )
+                }
+            ),
+        try(string_case_insensitive("move:"))
+            .with((signed_number(), token(','), signed_number()))
+            .map(|(x, _, y)| KeyboardKey::MoveBy(x, y))
+    )
+}
+
+/// Parses a decimal number with an optional `+` or `-` sign.
+fn signed_number<I>() -> impl Parser<Input = I, Output = i32>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        optional(or(token('+'), token('-'))),
+        many1(digit()),
+    ).and_then(|(sign, n): (Option<char>, String)| {
+        let n = match sign {
+            Some('-') => format!("-{}", n),
+            _ => n,
+        };
+
+        n.parse::<i32>()
+            .map_err(|_|
+                StreamErrorFor::<I>::message_static_message(
+                    "number too large"
+                )
+            )
+    })
+}
+
+fn nop<I>() -> impl Parser<Input = I, Output = KeyboardKey>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    string_case_insensitive("Nop")
+        .map(|_| KeyboardKey::Nop)
+}
+
+fn whitespace_separator<I>() -> impl Parser<Input = I>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    skip_many1(space().or(tab()))
+}
+
+/// Parses a `repeat` or `repeat=150` attribute, written between the map kind
+/// and the trigger. Without an interval, the global `repeat_interval` is used.
+fn map_repeat<I>() -> impl Parser<Input = I, Output = Milliseconds>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        try(string("repeat")),
+        optional(token('=').with(milliseconds())),
+        whitespace_separator(),
+    ).map(|(_, interval, _)| interval.unwrap_or(0))
+}
+
+fn map_map<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(map_kind_map()),
+        whitespace_separator(),
+        optional(map_repeat()),
+        trigger(),
+        whitespace_separator(),
+        action_map()
+    ).map(|((span, kind), _, repeat, trigger, _, action)|
+        Map {
+            span: span,
+            trigger: trigger,
+            action: action,
+            kind: kind,
+            repeat: repeat,
+        }
+    )
+}
+
+fn map_cmd<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(map_kind_cmd()),
+        whitespace_separator(),
+        optional(map_repeat()),
+        trigger(),
+        whitespace_separator(),
+        action()
+    ).map(|((span, kind), _, repeat, trigger, _, action)|
+        Map {
+            span: span,
+            trigger: trigger,
+            action: action,
+            kind: kind,
+            repeat: repeat,
+        }
+    )
+}
+
+fn map_text<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(map_kind_text()),
+        whitespace_separator(),
+        optional(map_repeat()),
+        trigger(),
+        whitespace_separator(),
+        action()
+    ).map(|((span, kind), _, repeat, trigger, _, action)|
+        Map {
+            span: span,
+            trigger: trigger,
+            action: action,
+            kind: kind,
+            repeat: repeat,
+        }
+    )
+}
+
+/// Parses a `do` block, whose steps can mix keys, text, commands, and waits:
+///
+/// ```text
+/// do <play><up> stoponerror timeout=30000 {
+///     cmd make;
+///     keys <D-Tab>; wait 100; text Built
+/// }
+/// ```
+///
+/// Steps end with `;` or a newline, including the last one before `}`, and
+/// spaces before the end are ignored. Write `\;` for a semicolon in a step.
+/// Commands in a `do` block finish before the next step starts. A command
+/// still running after `timeout` milliseconds, or `COMMAND_TIMEOUT` by
+/// default, is stopped and counts as a failure.
+fn map_do<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(map_kind_do()),
+        whitespace_separator(),
+        optional(map_repeat()),
+        trigger(),
+        whitespace_separator(),
+        do_block(),
+    ).map(|((span, kind), _, repeat, trigger, _, action)|
+        Map {
+            span: span,
+            trigger: trigger,
+            action: action,
+            kind: kind,
+            repeat: repeat,
+        }
+    )
+}
+
+/// Parses the options and steps of a `do` block after its trigger.
+fn do_block<I>() -> impl Parser<Input = I, Output = Action>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        optional(try(string("stoponerror")).skip(whitespace_separator())),
+        optional(block_timeout()),
+        steps(),
+    ).map(|(stop_on_error, timeout, steps)|
+        Action::Steps {
+            steps: steps,
+            stop_on_error: stop_on_error.is_some(),
+            timeout: timeout.unwrap_or(COMMAND_TIMEOUT),
+        }
+    )
+}
+
+/// Parses the `timeout=N` option of a `do` block or `if`, and the whitespace
+/// after it.
+fn block_timeout<I>() -> impl Parser<Input = I, Output = Milliseconds>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    try(string("timeout="))
+        .with(milliseconds())
+        .and_then(|ms|
+            if ms == 0 {
+                Err(
+                    StreamErrorFor::<I>::message_static_message(
+                        "timeout must be greater than 0"
+                    )
+                )
+            } else {
+                Ok(ms)
+            }
+        )
+        .skip(whitespace_separator())
+}
+
+/// Parses an `if`, which runs the first action if a command exits
+/// successfully, or the optional `else` action if it fails:
+///
+/// ```text
+/// if <play><down> `pgrep -q openvpn` timeout=2000 {
+///     cmd vpn disconnect
+/// } else if `ping -c 1 -t 1 vpn.example.com` {
+///     cmd vpn connect
+/// } else toggle {
+///     text offline
+/// } {
+///     text still offline
+/// }
+/// ```
+///
+/// Each action is a block of steps, or a `do`, `if`, `toggle`, or `cycle`
+/// without a trigger. A command still running after `timeout` milliseconds,
+/// or `CONDITION_TIMEOUT` by default, is stopped and counts as a failure.
+fn map_if<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(map_kind_if()),
+        whitespace_separator(),
+        optional(map_repeat()),
+        trigger(),
+        whitespace_separator(),
+        if_block(),
+    ).map(|((span, kind), _, repeat, trigger, _, action)|
+        Map {
+            span: span,
+            trigger: trigger,
+            action: action,
+            kind: kind,
+            repeat: repeat,
+        }
+    )
+}
+
+/// Parses the condition, options, and actions of an `if` after its trigger.
+fn if_block<I>() -> impl Parser<Input = I, Output = Action>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        between(
+            token('`'),
+            token('`'),
+            many1(satisfy(|c| c != '`' && c != '\n')),
+        ),
+        whitespace_separator(),
+        optional(block_timeout()),
+        branch(),
+        optional(
+            try((whitespace_separator(), string("else")))
+                .with(whitespace_separator())
+                .with(branch())
+        ),
+    ).map(|(condition, _, timeout, then, otherwise)|
+        Action::Conditional {
+            condition: condition,
+            timeout: timeout.unwrap_or(CONDITION_TIMEOUT),
+            then: Box::new(then),
+            otherwise: otherwise.map(Box::new),
+        }
+    )
+}
+
+// Branches can contain `if`s, so the parser is a named type to avoid an
+// infinitely recursive `impl Parser`.
+parser!{
+    fn branch[I]()(I) -> MapAction
+    where [
+        I: Stream<Item = char>,
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+        I::Position: Into<Span>,
+    ]
+    {
+        branch_()
+    }
+}
+
+/// Parses the action of an `if` branch: a block of steps, or a `do`, `if`,
+/// `toggle`, or `cycle` without a trigger.
+fn branch_<I>() -> impl Parser<Input = I, Output = MapAction>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    choice!(
+        steps()
+            .map(|steps| (
+                MapKind::Do,
+                Action::Steps {
+                    steps: steps,
+                    stop_on_error: false,
+                    timeout: COMMAND_TIMEOUT,
+                },
+            )),
+        (map_kind_do(), whitespace_separator(), do_block())
+            .map(|(kind, _, action)| (kind, action)),
+        (map_kind_if(), whitespace_separator(), if_block())
+            .map(|(kind, _, action)| (kind, action)),
+        (cycle_kind(), whitespace_separator(), cycle_blocks())
+            .and_then(|(is_toggle, _, (persist, actions))|
+                cycle_action(is_toggle, persist, actions)
+                    .map(|action| (MapKind::Cycle, action))
+                    .map_err(StreamErrorFor::<I>::message_static_message)
+            )
+    ).map(|(kind, action)|
+        MapAction {
+            action: action,
+            kind: kind,
+            repeat: None,
+        }
+    )
+}
+
+/// Parses a `toggle` or `cycle`, which run the next block of steps each time
+/// they're pressed:
+///
+/// ```text
+/// toggle <play><play> { keys <Mute>; text muted; } { keys <Mute>; }
+/// cycle <up><up> persist {
+///     cmd speed 1
+/// } {
+///     cmd speed 1.5
+/// } {
+///     cmd speed 2
+/// }
+/// ```
+///
+/// A `toggle` has two blocks, and a `cycle` at least two. With `persist`, the
+/// position is saved across restarts.
+fn map_cycle<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(cycle_kind()),
+        whitespace_separator(),
+        optional(map_repeat()),
+        trigger(),
+        whitespace_separator(),
+        cycle_blocks(),
+    ).and_then(|(
+        (span, is_toggle), _, repeat, trigger, _, (persist, actions),
+    )|
+        cycle_action(is_toggle, persist, actions)
+            .map(|action|
+                Map {
+                    span: span,
+                    trigger: trigger,
+                    action: action,
+                    kind: MapKind::Cycle,
+                    repeat: repeat,
+                }
+            )
+            .map_err(StreamErrorFor::<I>::message_static_message)
+    )
+}
+
+/// Parses `toggle` or `cycle`, returning whether it's a `toggle`.
+fn cycle_kind<I>() -> impl Parser<Input = I, Output = bool>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    or(
+        try(string("toggle")).map(|_| true),
+        try(string("cycle")).map(|_| false),
+    )
+}
+
+/// Parses the optional `persist` and the blocks of a `toggle` or `cycle`
+/// after its trigger.
+fn cycle_blocks<I>() -> impl Parser<Input = I, Output = (bool, Vec<Vec<Step>>)>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        optional(try(string("persist")).skip(whitespace_separator())),
+        steps(),
+        many::<Vec<Vec<Step>>, _>(try(blank().with(steps()))),
+    ).map(|(persist, first, rest)| {
+        let mut actions = vec![first];
+        actions.extend(rest);
+
+        (persist.is_some(), actions)
+    })
+}
+
+/// A `toggle` or `cycle` running `actions`, checking that it has enough of
+/// them.
+fn cycle_action(
+    is_toggle: bool,
+    persist: bool,
+    actions: Vec<Vec<Step>>,
+) -> Result<Action, &'static str> {
+    if is_toggle && actions.len() != 2 {
+        return Err("toggle needs two actions");
+    } else if actions.len() < 2 {
+        return Err("cycle needs at least two actions");
+    }
+
+    Ok(
+        Action::Cycle {
+            key: String::new(),
+            actions: actions,
+            persist: persist,
+        }
+    )
+}
+
+/// Parses a block of steps for `do`, `if`, `toggle`, or `cycle`.
+fn steps<I>() -> impl Parser<Input = I, Output = Vec<Step>>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        token('{'),
+        blank(),
+        many::<Vec<Step>, _>(step().skip(blank())),
+        token('}')
+            .message("missing closing '}'"),
+    ).map(|(_, _, steps, _)| steps)
+}
+
+/// Parses a step in a `do` block, including its terminating `;` or newline.
+fn step<I>() -> impl Parser<Input = I, Output = Step>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    choice!(
+        (try(string("keys")), whitespace_separator())
+            .with(many(
+                choice!(
+                    step_key_character()
+                        .map(|c| (
+                            Some(c),
+                            vec![KeyboardKeyWithModifiers::new(
+                                KeyboardKey::Character(Character::new(c)),
+                                vec![],
+                            )],
+                        )),
+                    special_key().map(|keys| (None, keys))
+                )
+            ))
+            .map(|mut keys: Vec<(Option<char>, Vec<_>)>| {
+                // Spaces before the end of the step aren't typed
+                while keys.last().map_or(false, |&(c, _)|
+                    c.map_or(false, char::is_whitespace)
+                ) {
+                    keys.pop();
+                }
+
+                Step::Keys(
+                    keys.into_iter().flat_map(|(_, keys)| keys).collect()
+                )
+            }),
+        (try(string("text")), whitespace_separator())
+            .with(many1(step_character()))
+            .map(|text: String| Step::Text(text.trim_end().to_owned())),
+        (try(string("cmd")), whitespace_separator())
+            .with(many1(step_character()))
+            .map(|command: String|
+                Step::Command(command.trim_end().to_owned())
+            ),
+        (try(string("wait")), whitespace_separator())
+            .with(milliseconds())
+            .skip(optional(whitespace_separator()))
+            .map(|ms| Step::Wait(ms))
+    ).skip(or(token(';'), newline()))
+}
+
+/// Parses a character in a `text` or `cmd` step. A step ends at `;` unless
+/// it's escaped as `\;`.
+fn step_character<I>() -> impl Parser<Input = I, Output = char>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    or(
+        try(string("\\;")).map(|_| ';'),
+        satisfy(|c| c != ';' && c != '\n'),
+    )
+}
+
+/// Parses a character in a `keys` step, like `action_character` but also
+/// ending at `;`.
+fn step_key_character<I>() -> impl Parser<Input = I, Output = char>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice!(
+        satisfy(|c| c != '<' && c != '\\' && c != '\n' && c != ';'),
+        try(string("\\;")).map(|_| ';'),
+        action_escape()
+    )
+}
+
+fn map<I>() -> impl Parser<Input = I, Output = Map>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    choice!(
+        map_map(),
+        map_cmd(),
+        map_text(),
+        map_do(),
+        map_if(),
+        map_cycle()
+    )
+}
+
+fn maps<I>() -> impl Parser<Input = I, Output = Vec<Map>>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    many1(map().skip(blank()))
+}
+
+fn map_collection<I>() -> impl Parser<Input = I, Output = Vec<Map>>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        blank(),
+        maps(),
+    ).map(|(_, collection)| collection)
+}
+
+fn mode_hook_action<I>() -> impl Parser<Input = I, Output = MapAction>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    or(
+        (
+            map_kind_map(),
+            whitespace_separator(),
+            action_map(),
+        ),
+        (
+            or(map_kind_cmd(), map_kind_text()),
+            whitespace_separator(),
+            action(),
+        ),
+    ).map(|(kind, _, action)|
+        MapAction {
+            action: action,
+            kind: kind,
+            repeat: None,
+        }
+    )
+}
+
+/// Parses an `on_enter` or `on_exit` action, run when the enclosing mode is
+/// activated or deactivated.
+fn mode_hook<I>() -> impl Parser<Input = I, Output = ModeHook>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    or(
+        (
+            try(string("on_enter")),
+            whitespace_separator(),
+            mode_hook_action(),
+        ).map(|(_, _, action)| ModeHook::Enter(action)),
+        (
+            try(string("on_exit")),
+            whitespace_separator(),
+            mode_hook_action(),
+        ).map(|(_, _, action)| ModeHook::Exit(action)),
+    )
+}
+
+fn milliseconds<I>() -> impl Parser<Input = I, Output = Milliseconds>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    many1(digit())
+        .and_then(|ms: String|
+            ms.parse::<Milliseconds>()
+                .map_err(|_|
+                    StreamErrorFor::<I>::message_static_message(
+                        "number of milliseconds too large"
+                    )
+                )
+        )
+}
+
+/// Parses a mode setting written between the mode trigger and its opening
+/// brace, like `timeout=5000`, `oneshot`, or `fallthrough`.
+fn mode_attribute<I>() -> impl Parser<Input = I, Output = ModeAttribute>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice!(
+        (
+            try(string("timeout=")),
+            milliseconds(),
+        ).map(|(_, ms)| ModeAttribute::Timeout(ms)),
+        try(string("oneshot")).map(|_| ModeAttribute::Oneshot),
+        try(string("fallthrough")).map(|_| ModeAttribute::Fallthrough)
+    )
+}
+
+/// Parses a quoted mode or device name like `"Editing"`.
+fn quoted_name<I>() -> impl Parser<Input = I, Output = CString>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    between(
+        token('"'),
+        token('"'),
+        many1(satisfy(|c| c != '"' && c != '\n')),
+    ).and_then(|name: String|
+        CString::new(name)
+            .map_err(|_|
+                StreamErrorFor::<I>::message_static_message(
+                    "name contains a null character"
+                )
+            )
+    )
+}
+
+fn mode<I>() -> impl Parser<Input = I, Output = ModeDefinition>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        spanned(string("mode")),
+        whitespace_separator(),
+        optional(quoted_name().skip(whitespace_separator())),
+        trigger(),
+        whitespace_separator(),
+        many::<Vec<ModeAttribute>, _>(
+            mode_attribute().skip(whitespace_separator())
+        ),
         token('{'),
-        map_collection(),
+        blank(),
+        many::<Vec<ModeItem>, _>(
+            or(
+                mode_hook().map(ModeItem::Hook),
+                map().map(ModeItem::Map),
+            ).skip(blank())
+        ),
         token('}')
             .message("missing closing '}'"),
-    ).map(|(_, _, trigger, _, _, collection, _)|
-        Mode {
+    ).map(|((span, _), _, name, trigger, _, attributes, _, _, items, _)| {
+        let mut mode = ModeDefinition {
+            span: span,
             trigger: trigger,
-            maps: collection,
+            name: name,
+            maps: vec![],
+            on_enter: None,
+            on_exit: None,
+            options: ModeOptions::default(),
+        };
+
+        for attribute in attributes {
+            match attribute {
+                ModeAttribute::Timeout(ms) => mode.options.timeout = Some(ms),
+                ModeAttribute::Oneshot => mode.options.oneshot = true,
+                ModeAttribute::Fallthrough => mode.options.fallthrough = true,
+            }
+        }
+
+        for item in items {
+            match item {
+                ModeItem::Hook(ModeHook::Enter(action)) =>
+                    mode.on_enter = Some(action),
+                ModeItem::Hook(ModeHook::Exit(action)) =>
+                    mode.on_exit = Some(action),
+                ModeItem::Map(map) => mode.maps.push(map),
+            }
+        }
+
+        mode
+    })
+}
+
+/// Parses an `escape` directive, which sets a trigger that leaves any active
+/// mode.
+fn escape<I>() -> impl Parser<Input = I, Output = UnresolvedTrigger>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        try(string("escape")),
+        whitespace_separator(),
+        trigger(),
+    ).map(|(_, _, trigger)| trigger)
+}
+
+/// Parses a `conflict = <policy>` setting, which decides what happens when a
+/// trigger is both a map and a mode.
+fn conflict<I>() -> impl Parser<Input = I, Output = ConflictPolicy>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        try(string("conflict")),
+        optional(whitespace_separator()),
+        token('='),
+        optional(whitespace_separator()),
+        choice!(
+            try(string("map-first")).map(|_| ConflictPolicy::MapFirst),
+            try(string("mode-only")).map(|_| ConflictPolicy::ModeOnly),
+            try(string("error")).map(|_| ConflictPolicy::Error)
+        ),
+    ).map(|(_, _, _, _, policy)| policy)
+}
+
+/// Parses an `alias name = N` directive, which names the button with code
+/// `N`. The name can be used in any trigger in the file.
+fn alias<I>() -> impl Parser<Input = I, Output = (String, ButtonCode)>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        try(string("alias")),
+        whitespace_separator(),
+        button_name(),
+        optional(whitespace_separator()),
+        token('='),
+        optional(whitespace_separator()),
+        many1::<String, _>(digit()),
+    ).and_then(|(_, _, name, _, _, _, code)| {
+        if is_builtin_button_name(&name) {
+            return Err(
+                StreamErrorFor::<I>::message_static_message(
+                    "can't alias a built-in button name"
+                )
+            );
+        }
+
+        match code.parse::<ButtonCode>() {
+            Ok(code) if code <= MAX_BUTTON_CODE =>
+                Ok((name.to_lowercase(), code)),
+            _ => Err(
+                StreamErrorFor::<I>::message_static_message(
+                    "button code too large"
+                )
+            ),
+        }
+    })
+}
+
+/// Parses a `device "Name" { ... }` block, containing mappings that only
+/// apply to triggers from the named device.
+fn device<I>() -> impl Parser<Input = I, Output = (CString, Vec<Map>)>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        try(string("device")),
+        whitespace_separator(),
+        quoted_name(),
+        whitespace_separator(),
+        token('{'),
+        map_collection(),
+        token('}')
+            .message("missing closing '}'"),
+    ).map(|(_, _, name, _, _, collection, _)| (name, collection))
+}
+
+fn definitions<I>() -> impl Parser<Input = I, Output = Vec<Definition>>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Position: Into<Span>,
+{
+    (
+        blank(),
+        many1(
+            choice!(
+                spanned(escape())
+                    .map(|(span, trigger)| Definition::Escape(trigger, span)),
+                conflict().map(|policy| Definition::Conflict(policy)),
+                alias().map(|(name, code)| Definition::Alias(name, code)),
+                device().map(|(name, maps)| Definition::Device(name, maps)),
+                map().map(|map| Definition::Map(map)),
+                mode().map(|mode| Definition::Mode(mode))
+            ).skip(blank())
+        )
+    ).map(|(_, definitions)| definitions)
+}
+
+fn map_group<I>() -> impl Parser<Input = I, Output = MapGroup>
+where
+    I: Stream<Item = char, Position = SourcePosition>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    or(
+        try((
+            blank(),
+            eof(),
+        )).map(|_| MapGroup::default()),
+        parser(|input: &mut I| {
+            let start = input.position();
+            let ((definitions, _), consumed) = (
+                definitions(),
+                eof(),
+            ).parse_stream(input)?;
+
+            match resolve_definitions(definitions) {
+                Ok(map_group) => Ok((map_group, consumed)),
+
+                // Point the error at the definition it's about rather than
+                // the start of the file
+                Err(e) => {
+                    let mut error = I::Error::empty(e.span.0.unwrap_or(start));
+
+                    if let Some(c) = e.unexpected {
+                        error.add(StreamErrorFor::<I>::unexpected_token(c));
+                    }
+
+                    error.add(StreamErrorFor::<I>::message_message(e.message));
+
+                    Err(Consumed::Consumed(error.into()))
+                },
+            }
+        }),
+    )
+}
+
+/// Build a map group from the definitions in a file, resolving aliases and
+/// checking the parts that depend on each other.
+fn resolve_definitions(
+    definitions: Vec<Definition>,
+) -> Result<MapGroup, DefinitionError> {
+    let mut map_group = MapGroup::default();
+    let mut map_triggers = TriggerSpans::new();
+    let mut mode_spans = HashMap::new();
+    let mut mode_names = HashSet::new();
+    let mut spans = TriggerSpans::new();
+    let mut escape_span = Span::default();
+
+    let aliases: Aliases = definitions
+        .iter()
+        .filter_map(|definition| match *definition {
+            Definition::Alias(ref name, code) =>
+                Some((name.clone(), code)),
+            _ => None,
+        })
+        .collect();
+
+    for definition in definitions {
+        match definition {
+            Definition::Map(map) => {
+                let span = map.span;
+                let (trigger, map_action) = map.resolve(&aliases)?;
+
+                spans.push((trigger.clone(), span));
+                map_triggers.push((trigger.clone(), span));
+                map_group.maps.insert(trigger, map_action);
+            },
+            Definition::Mode(mode) => {
+                let span = mode.span;
+                let mode = mode.resolve(&aliases, &mut spans)?;
+
+                if let Some(ref name) = mode.name {
+                    if !mode_names.insert(name.clone()) {
+                        return Err(DefinitionError::new(
+                            span,
+                            format!(
+                                "mode '{}' defined twice",
+                                name.to_string_lossy(),
+                            ),
+                        ));
+                    }
+                }
+
+                spans.push((mode.trigger.clone(), span));
+                mode_spans.insert(mode.trigger.clone(), span);
+                map_group.modes.insert(
+                    mode.trigger.clone(),
+                    mode,
+                );
+            },
+            Definition::Escape(trigger, span) => {
+                let trigger = resolve_trigger(trigger, &aliases)?;
+
+                map_group.escape = Some(trigger);
+                escape_span = span;
+            },
+            Definition::Conflict(policy) => {
+                map_group.conflict = policy;
+            },
+
+            // Collected before resolving triggers
+            Definition::Alias(_, _) => (),
+            Definition::Device(name, maps) => {
+                let mut device_spans = TriggerSpans::new();
+                let maps = resolve_maps(maps, &aliases, &mut device_spans)?;
+
+                map_triggers.extend(device_spans.iter().cloned());
+                spans.extend(device_spans);
+
+                map_group.devices
+                    .entry(name)
+                    .or_insert_with(HashMap::new)
+                    .extend(maps);
+            },
         }
+    }
+
+    if map_group.conflict == ConflictPolicy::Error {
+        // Point at whichever of the map and the mode comes second
+        let conflict = map_triggers
+            .iter()
+            .filter_map(|&(ref trigger, span)|
+                mode_spans
+                    .get(trigger)
+                    .map(|mode_span| Span(cmp::max(span.0, mode_span.0)))
+            )
+            .min_by_key(|span| span.0);
+
+        if let Some(span) = conflict {
+            return Err(DefinitionError::new(
+                span,
+                "trigger is both a map and a mode".to_owned(),
+            ));
+        }
+
+        // Modes replace default mappings on the same trigger
+        let modes = &map_group.modes;
+        map_group.maps.retain(|trigger, _| !modes.contains_key(trigger));
+    }
+
+    if let Some((name, span)) = map_group.undefined_mode_name() {
+        return Err(DefinitionError::new(
+            span,
+            format!("no mode named '{}'", name.to_string_lossy()),
+        ));
+    }
+
+    if let Some(ref escape) = map_group.escape {
+        map_group.check_escape(escape).map_err(|e| {
+            // Point at the first mapping that shadows the escape, or at the
+            // escape itself if it's a default one
+            let span = spans
+                .iter()
+                .find(|&&(ref trigger, _)| trigger == escape)
+                .map_or(escape_span, |&(_, span)| span);
+
+            DefinitionError::new(span, e.to_owned())
+        })?;
+    }
+
+    map_group.key_cycles();
+
+    Ok(map_group)
+}
+
+fn comment<I>() -> impl Parser<Input = I>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        token('#'),
+        skip_many(satisfy(|c| c != '\n')),
     )
 }
 
-fn definitions<I>() -> impl Parser<Input = I, Output = Vec<Definition>>
-where
-    I: Stream<Item = char>,
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
-{
-    (
-        blank(),
-        many1(
-            choice!(
-                map().map(|map| Definition::Map(map)),
-                mode().map(|mode| Definition::Mode(mode))
-            ).skip(blank())
-        )
-    ).map(|(_, definitions)| definitions)
-}
 
-fn map_group<I>() -> impl Parser<Input = I, Output = MapGroup>
-where
-    I: Stream<Item = char>,
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
-{
-    or(
-        try((
-            blank(),
-            eof(),
-        )).map(|_| MapGroup::default()),
-        (
-            definitions(),
-            eof(),
-        ).map(|(definitions, _)| {
-            let mut map_group = MapGroup::default();
-
-            for definition in definitions {
-                match definition {
-                    Definition::Map(map) => {
-                        map_group.maps.insert(
-                            map.trigger,
-                            MapAction {
-                                action: map.action,
-                                kind: map.kind,
-                            }
-                        );
-                    },
-                    Definition::Mode(mode) => {
-                        map_group.modes.insert(
-                            mode.trigger,
-                            mode.maps,
-                        );
-                    },
+fn blank<I>() -> impl Parser<Input = I>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let comment = comment().map(|_| ());
+    let whitespace = whitespace_separator().map(|_| ());
+    skip_many(skip_many1(newline()).or(whitespace).or(comment))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `if` branch of a block of steps.
+    fn block(steps: Vec<Step>) -> Box<MapAction> {
+        Box::new(MapAction {
+            action: Action::Steps {
+                steps: steps,
+                stop_on_error: false,
+                timeout: COMMAND_TIMEOUT,
+            },
+            kind: MapKind::Do,
+            repeat: None,
+        })
+    }
+
+    /// A trigger as `trigger()` parses it when it doesn't use aliases.
+    fn unresolved(trigger: Vec<HeadphoneButton>) -> UnresolvedTrigger {
+        trigger.into_iter().map(TriggerButton::Button).collect()
+    }
+
+    #[test]
+    fn map_kind_map_parses_kind_map() {
+        let text = "map";
+        let result = map_kind_map().parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(MapKind::Map));
+    }
+
+    #[test]
+    fn map_kind_cmd_parses_kind_command() {
+        let text = "cmd";
+        let result = map_kind_cmd().parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(MapKind::Command));
+    }
+
+    #[test]
+    fn map_kind_text_parses_kind_text() {
+        let text = "text";
+        let result = map_kind_text().parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(MapKind::Text));
+    }
+
+    #[test]
+    fn map_parses_do_block() {
+        let text = "do <play><up> stoponerror timeout=2000 { \
+            keys <D-c>; wait 100; cmd pbpaste | wc -c\\; echo; # Count
+            text a\\;b
+        }";
+        let result = map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(
+            result,
+            Ok(
+                Map {
+                    span: Span::default(),
+                    trigger: unresolved(
+                        vec![HeadphoneButton::Play, HeadphoneButton::Up]
+                    ),
+                    action: Action::Steps {
+                        steps: vec![
+                            Step::Keys(vec![
+                                KeyboardKeyWithModifiers::new(
+                                    KeyboardKey::Character(Character::new('c')),
+                                    vec![Flag::Meta],
+                                ),
+                            ]),
+                            Step::Wait(100),
+                            Step::Command("pbpaste | wc -c; echo".to_owned()),
+                            Step::Text("a;b".to_owned()),
+                        ],
+                        stop_on_error: true,
+                        timeout: 2000,
+                    },
+                    kind: MapKind::Do,
+                    repeat: None,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn steps_ignore_spaces_before_the_end_of_a_step() {
+        let text = "{ keys a <Up> ; text hi  ; cmd echo\t;
+            wait 100 ;
+        }";
+        let result = steps().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(
+            result,
+            Ok(vec![
+                Step::Keys(vec![
+                    KeyboardKeyWithModifiers::new(
+                        KeyboardKey::Character(Character::new('a')),
+                        vec![],
+                    ),
+                    KeyboardKeyWithModifiers::new(
+                        KeyboardKey::Character(Character::new(' ')),
+                        vec![],
+                    ),
+                    KeyboardKeyWithModifiers::new(
+                        KeyboardKey::KeyCode(KeyCode::new(
+                            autopilot::key::KeyCode::UpArrow
+                        )),
+                        vec![],
+                    ),
+                ]),
+                Step::Text("hi".to_owned()),
+                Step::Command("echo".to_owned()),
+                Step::Wait(100),
+            ])
+        );
+    }
+
+    #[test]
+    fn steps_without_separator_before_closing_brace_fail() {
+        let text = "{ wait 100 }";
+        let result = steps().easy_parse(text).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Expected(';'.into()),
+        ));
+    }
+
+    #[test]
+    fn map_parses_if() {
+        let text = "if <play><down> `pgrep -q \"open vpn\"` timeout=2000 {
+            cmd vpn disconnect
+        } else {
+            text connect; keys <enter>
+        }";
+        let result = map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(
+            result,
+            Ok(
+                Map {
+                    span: Span::default(),
+                    trigger: unresolved(
+                        vec![HeadphoneButton::Play, HeadphoneButton::Down]
+                    ),
+                    action: Action::Conditional {
+                        condition: "pgrep -q \"open vpn\"".to_owned(),
+                        timeout: 2000,
+                        then: block(vec![
+                            Step::Command("vpn disconnect".to_owned()),
+                        ]),
+                        otherwise: Some(block(vec![
+                            Step::Text("connect".to_owned()),
+                            Step::Keys(vec![
+                                KeyboardKeyWithModifiers::new(
+                                    KeyboardKey::KeyCode(KeyCode::new(
+                                        autopilot::key::KeyCode::Return
+                                    )),
+                                    vec![],
+                                ),
+                            ]),
+                        ])),
+                    },
+                    kind: MapKind::If,
+                    repeat: None,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn map_parses_if_without_else() {
+        let text = "if <play> `true` { cmd echo; }";
+        let result = map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(
+            result.map(|map| map.action),
+            Ok(
+                Action::Conditional {
+                    condition: "true".to_owned(),
+                    timeout: CONDITION_TIMEOUT,
+                    then: block(vec![Step::Command("echo".to_owned())]),
+                    otherwise: None,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn map_parses_if_with_nested_actions() {
+        let text = "if <play> `true` do stoponerror { cmd a; } \
+            else if `false` timeout=50 { text b; } \
+            else toggle { text c; } { text d; }";
+        let result = map().easy_parse(text).map(|t| t.0.action);
+
+        assert_eq!(
+            result,
+            Ok(
+                Action::Conditional {
+                    condition: "true".to_owned(),
+                    timeout: CONDITION_TIMEOUT,
+                    then: Box::new(MapAction {
+                        action: Action::Steps {
+                            steps: vec![Step::Command("a".to_owned())],
+                            stop_on_error: true,
+                            timeout: COMMAND_TIMEOUT,
+                        },
+                        kind: MapKind::Do,
+                        repeat: None,
+                    }),
+                    otherwise: Some(Box::new(MapAction {
+                        action: Action::Conditional {
+                            condition: "false".to_owned(),
+                            timeout: 50,
+                            then: block(vec![Step::Text("b".to_owned())]),
+                            otherwise: Some(Box::new(MapAction {
+                                action: Action::Cycle {
+                                    key: String::new(),
+                                    actions: vec![
+                                        vec![Step::Text("c".to_owned())],
+                                        vec![Step::Text("d".to_owned())],
+                                    ],
+                                    persist: false,
+                                },
+                                kind: MapKind::Cycle,
+                                repeat: None,
+                            })),
+                        },
+                        kind: MapKind::If,
+                        repeat: None,
+                    })),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn map_if_with_zero_timeout_fails() {
+        let text = "if <play> `true` timeout=0 { cmd echo; }";
+        let result = map().easy_parse(text).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("timeout must be greater than 0".into()),
+        ));
+    }
+
+    #[test]
+    fn map_if_with_toggle_of_one_action_fails() {
+        let text = "if <play> `true` toggle { cmd echo; }";
+        let result = map().easy_parse(text).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("toggle needs two actions".into()),
+        ));
+    }
+
+    #[test]
+    fn map_parses_toggle_and_cycle() {
+        let text = "toggle <play> { keys <Mute>; } { text b; }";
+        let result = map().easy_parse(text).map(|t| t.0.action);
+
+        assert_eq!(
+            result,
+            Ok(
+                Action::Cycle {
+                    key: String::new(),
+                    actions: vec![
+                        vec![
+                            Step::Keys(vec![
+                                KeyboardKeyWithModifiers::new(
+                                    KeyboardKey::NXKey(
+                                        key_code::NX_KEYTYPE_MUTE
+                                    ),
+                                    vec![],
+                                ),
+                            ]),
+                        ],
+                        vec![Step::Text("b".to_owned())],
+                    ],
+                    persist: false,
                 }
-            }
+            )
+        );
 
-            map_group
-        }),
-    )
-}
+        let text = "cycle <up> persist {
+            cmd speed 1
+        } {
+            cmd speed 2
+        }
+        { cmd speed 3; }";
+        let result = map().easy_parse(text).map(|t| t.0.action);
 
-fn comment<I>() -> impl Parser<Input = I>
-where
-    I: Stream<Item = char>,
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
-{
-    (
-        token('#'),
-        skip_many(satisfy(|c| c != '\n')),
-    )
-}
+        assert_eq!(
+            result,
+            Ok(
+                Action::Cycle {
+                    key: String::new(),
+                    actions: vec![
+                        vec![Step::Command("speed 1".to_owned())],
+                        vec![Step::Command("speed 2".to_owned())],
+                        vec![Step::Command("speed 3".to_owned())],
+                    ],
+                    persist: true,
+                }
+            )
+        );
+    }
 
+    #[test]
+    fn map_with_wrong_number_of_toggle_actions_fails() {
+        let text = "toggle <play> { text a; } { text b; } { text c; }";
+        let result = map().easy_parse(text).map(|t| t.0);
 
-fn blank<I>() -> impl Parser<Input = I>
-where
-    I: Stream<Item = char>,
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
-{
-    let comment = comment().map(|_| ());
-    let whitespace = whitespace_separator().map(|_| ());
-    skip_many(skip_many1(newline()).or(whitespace).or(comment))
-}
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("toggle needs two actions".into())
+        ));
 
+        let text = "cycle <play> { text a; }";
+        let result = map().easy_parse(text).map(|t| t.0);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("cycle needs at least two actions".into())
+        ));
+    }
 
     #[test]
-    fn map_kind_map_parses_kind_map() {
-        let text = "map";
-        let result = map_kind_map().parse(text).map(|t| t.0);
+    fn map_group_keys_cycles_by_scope_and_trigger() {
+        let text = "cycle <play><up> { text a; } { text b; }
+            mode <down> {
+                toggle <up> { text a; } { text b; }
+            }
+            device \"Pedal\" {
+                toggle <play> { text a; } { text b; }
+            }";
+        let map_group = MapGroup::parse(text).unwrap();
+
+        let key = |map_action: &MapAction| match map_action.action {
+            Action::Cycle { ref key, .. } => key.clone(),
+            _ => panic!("not a cycle"),
+        };
+        let pedal = CString::new("Pedal").unwrap();
 
-        assert_eq!(result, Ok(MapKind::Map));
+        assert_eq!(
+            key(&map_group.maps[&vec![
+                HeadphoneButton::Play,
+                HeadphoneButton::Up,
+            ]]),
+            "0-1"
+        );
+        assert_eq!(
+            key(&map_group.modes[&vec![HeadphoneButton::Down]]
+                .maps[&vec![HeadphoneButton::Up]]),
+            "mode 2 1"
+        );
+        assert_eq!(
+            key(&map_group.devices[&pedal][&vec![HeadphoneButton::Play]]),
+            "device \"Pedal\" 0"
+        );
     }
 
     #[test]
-    fn map_kind_cmd_parses_kind_command() {
-        let text = "cmd";
-        let result = map_kind_cmd().parse(text).map(|t| t.0);
+    fn map_group_keys_cycles_in_if_branches() {
+        let text = "if <play> `true` toggle persist { text a; } { text b; } \
+            else if `true` { text c; } else cycle { text d; } { text e; }";
+        let map_group = MapGroup::parse(text).unwrap();
+
+        let mut persistent = map_group.persistent_cycles();
+        persistent.sort();
+
+        assert_eq!(persistent, vec!["0 then"]);
+
+        let otherwise = match map_group.maps[&vec![HeadphoneButton::Play]]
+            .action
+        {
+            Action::Conditional { otherwise: Some(ref otherwise), .. } =>
+                otherwise,
+            _ => panic!("not an if with an else"),
+        };
 
-        assert_eq!(result, Ok(MapKind::Command));
+        match otherwise.action {
+            Action::Conditional {
+                otherwise: Some(ref otherwise),
+                ..
+            } => match otherwise.action {
+                Action::Cycle { ref key, .. } =>
+                    assert_eq!(key, "0 else else"),
+                _ => panic!("not a cycle"),
+            },
+            _ => panic!("not an if with an else"),
+        }
+    }
+
+    #[test]
+    fn map_group_lists_persistent_cycles() {
+        let text = "cycle <play> persist { text a; } { text b; }
+            toggle <up> { text a; } { text b; }
+            mode <down> {
+                toggle <up> persist { text a; } { text b; }
+            }";
+        let map_group = MapGroup::parse(text).unwrap();
+
+        let mut persistent = map_group.persistent_cycles();
+        persistent.sort();
+
+        assert_eq!(persistent, vec!["0", "mode 2 1"]);
+    }
+
+    #[test]
+    fn map_parses_text_map() {
+        let text = "text <play><down> Naïve — café, wörld\n";
+        let result = map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(
+            result,
+            Ok(
+                Map {
+                    span: Span::default(),
+                    trigger: unresolved(
+                        vec![HeadphoneButton::Play, HeadphoneButton::Down]
+                    ),
+                    action: Action::String("Naïve — café, wörld".to_owned()),
+                    kind: MapKind::Text,
+                    repeat: None,
+                }
+            )
+        );
     }
 
     #[test]
@@ -756,7 +3331,7 @@ mod tests {
         let text = "<play>";
         let result = headphone_button().parse(text).map(|t| t.0);
 
-        assert_eq!(result, Ok(HeadphoneButton::Play));
+        assert_eq!(result, Ok(TriggerButton::Button(HeadphoneButton::Play)));
     }
 
     #[test]
@@ -764,7 +3339,63 @@ mod tests {
         let text = "<Play>";
         let result = headphone_button().parse(text).map(|t| t.0);
 
-        assert_eq!(result, Ok(HeadphoneButton::Play));
+        assert_eq!(result, Ok(TriggerButton::Button(HeadphoneButton::Play)));
+    }
+
+    #[test]
+    fn headphone_button_parses_long_and_double_presses() {
+        let text = "<play:long><Up:Double>";
+        let result = trigger().parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(unresolved(vec![
+            HeadphoneButton::PlayLong,
+            HeadphoneButton::UpDouble,
+        ])));
+    }
+
+    #[test]
+    fn headphone_button_parses_chords() {
+        let text = "<play+up><Down+Up+Play>";
+        let result = trigger().parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(unresolved(vec![
+            HeadphoneButton::PlayUp,
+            HeadphoneButton::PlayUpDown,
+        ])));
+    }
+
+    #[test]
+    fn headphone_button_with_repeated_chord_button_fails() {
+        let text = "<up+up>";
+        let result = headphone_button().easy_parse(text).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message(
+                "chords can only combine different play, up, and down buttons"
+                    .into()
+            ),
+        ));
+    }
+
+    #[test]
+    fn headphone_button_parses_button_code() {
+        let text = "<button:0><button:12:long>";
+        let result = trigger().parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(unresolved(vec![
+            HeadphoneButton::Play,
+            HeadphoneButton(12).long().unwrap(),
+        ])));
+    }
+
+    #[test]
+    fn headphone_button_with_button_code_too_large_fails() {
+        let text = "<button:65536>";
+        let result = headphone_button().easy_parse(text).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("button code too large".into()),
+        ));
     }
 
     #[test]
@@ -772,11 +3403,11 @@ mod tests {
         let text = "<up><down><play>";
         let result = trigger().parse(text).map(|t| t.0);
 
-        assert_eq!(result, Ok(vec![
+        assert_eq!(result, Ok(unresolved(vec![
             HeadphoneButton::Up,
             HeadphoneButton::Down,
             HeadphoneButton::Play,
-        ]));
+        ])));
     }
 
     #[test]
@@ -938,35 +3569,164 @@ mod tests {
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('a')),
+                KeyboardKey::Character(Character::new('a')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('d')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('y')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::F2)),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Space)),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('g')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('o')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Escape)),
+                vec![],
+            ),
+        ]);
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_parses_map_with_nx_key() {
+        let text = "<A-Play><Enter>";
+
+        let expected = Action::Map(vec![
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::NXKey(key_code::NX_KEYTYPE_PLAY),
+                vec![Flag::Alt],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Return)),
+                vec![],
+            ),
+        ]);
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_parses_map_with_backslash_escape() {
+        let text = "type\\\\onebslash";
+
+        let expected = Action::Map(vec![
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('t')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('y')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('p')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('e')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('\\')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('o')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('n')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('e')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('b')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('s')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('l')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('a')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('s')),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('h')),
+                vec![],
+            ),
+        ]);
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_parses_map_with_less_than_escape() {
+        let text = "type\\<lt>";
+
+        let expected = Action::Map(vec![
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Character(Character::new('t')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('d')),
+                KeyboardKey::Character(Character::new('y')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('y')),
+                KeyboardKey::Character(Character::new('p')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::F2)),
+                KeyboardKey::Character(Character::new('e')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Space)),
+                KeyboardKey::Character(Character::new('<')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('g')),
+                KeyboardKey::Character(Character::new('l')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('o')),
+                KeyboardKey::Character(Character::new('t')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Escape)),
+                KeyboardKey::Character(Character::new('>')),
                 vec![],
             ),
         ]);
@@ -976,16 +3736,12 @@ mod tests {
     }
 
     #[test]
-    fn action_parses_map_with_nx_key() {
-        let text = "<A-Play><Enter>";
+    fn action_parses_map_with_nop() {
+        let text = "<Nop>";
 
         let expected = Action::Map(vec![
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::NXKey(key_code::NX_KEYTYPE_PLAY),
-                vec![Flag::Alt],
-            ),
-            KeyboardKeyWithModifiers::new(
-                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Return)),
+                KeyboardKey::Nop,
                 vec![],
             ),
         ]);
@@ -995,64 +3751,105 @@ mod tests {
     }
 
     #[test]
-    fn action_parses_map_with_backslash_escape() {
-        let text = "type\\\\onebslash";
+    fn action_parses_map_with_mode_keys() {
+        let text = "<mode:Editing><exitmode>";
 
         let expected = Action::Map(vec![
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('t')),
-                vec![],
-            ),
-            KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('y')),
-                vec![],
-            ),
-            KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('p')),
+                KeyboardKey::Mode(
+                    CString::new("Editing").unwrap(),
+                    Span::default(),
+                ),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('e')),
+                KeyboardKey::ExitMode,
                 vec![],
             ),
+        ]);
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_parses_map_with_wait() {
+        let text = "<D-space><wait:200>a";
+
+        let expected = Action::Map(vec![
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('\\')),
-                vec![],
+                KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Space)),
+                vec![Flag::Meta],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('o')),
+                KeyboardKey::Wait(200),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('n')),
+                KeyboardKey::Character(Character::new('a')),
                 vec![],
             ),
-            KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('e')),
-                vec![],
+        ]);
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_parses_map_with_repeat_count() {
+        let text = "<3*Down>a";
+
+        let down = KeyboardKeyWithModifiers::new(
+            KeyboardKey::KeyCode(
+                KeyCode::new(autopilot::key::KeyCode::DownArrow)
             ),
+            vec![],
+        );
+        let expected = Action::Map(vec![
+            down.clone(),
+            down.clone(),
+            down,
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('b')),
+                KeyboardKey::Character(Character::new('a')),
                 vec![],
             ),
-            KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('s')),
-                vec![],
+        ]);
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_with_repeat_count_too_large_fails() {
+        let text = "<101*Down>";
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message(
+                "repeat count must be between 1 and 100".into()
             ),
+        ));
+    }
+
+    #[test]
+    fn action_parses_map_with_unicode_and_raw_key_codes() {
+        let text = "<U+00E9><code:0x35><C-code:53><nx:16>";
+
+        let expected = Action::Map(vec![
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('l')),
+                KeyboardKey::Character(Character::new('\u{e9}')),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('a')),
+                KeyboardKey::RawKeyCode(RawKeyCode(0x35)),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('s')),
-                vec![],
+                KeyboardKey::RawKeyCode(RawKeyCode(0x35)),
+                vec![Flag::Control],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('h')),
+                KeyboardKey::NXKey(key_code::NX_KEYTYPE_PLAY),
                 vec![],
             ),
         ]);
@@ -1062,40 +3859,117 @@ mod tests {
     }
 
     #[test]
-    fn action_parses_map_with_less_than_escape() {
-        let text = "type\\<lt>";
+    fn action_with_invalid_raw_key_code_fails() {
+        let result = action_map().easy_parse("<code:0x80>").map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("key code must be at most 0x7F".into())
+        ));
+
+        let result = action_map().easy_parse("<U+D800>").map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("invalid Unicode code point".into())
+        ));
+    }
+
+    #[test]
+    fn action_parses_every_key_name() {
+        let names = key_name::KEY_NAMES
+            .iter()
+            .flat_map(|key|
+                ::std::iter::once(&key.name).chain(key.aliases.iter())
+            );
+
+        for name in names {
+            let text = format!("<{}>", name);
+            let result = action_map().easy_parse(&text[..]).map(|t| t.0);
+
+            match result {
+                Ok(Action::Map(ref keys)) if keys.len() == 1 => (),
+                _ => panic!(
+                    "{} didn't parse as a single key: {:?}",
+                    name,
+                    result,
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn keyboard_key_with_modifiers_formats_as_map_action() {
+        let text = "a<C-x><enter><D-S-pageup><code:0x35><nx:30><U+00E9>\\\\\\<\
+            <down:D><up:D><Fn-Left><wait:20><mode:Other><exitmode><Nop><click><scroll:-3><move:+10,0>";
+        let keys = match action_map().easy_parse(text).map(|t| t.0) {
+            Ok(Action::Map(keys)) => keys,
+            result => panic!("{:?}", result),
+        };
+
+        let formatted = keys
+            .iter()
+            .map(|key| key.to_string())
+            .collect::<String>();
+
+        assert_eq!(
+            formatted,
+            "a<C-x><Return><D-S-PageUp><code:0x35><nx:30>\u{e9}<U+005C>\
+                <U+003C><down:Cmd><up:Cmd><down:Fn><Left><up:Fn><wait:20>\
+                <mode:Other><exitmode><Nop><click><scroll:-3><move:+10,0>"
+        );
+
+        let reparsed = action_map()
+            .easy_parse(&formatted[..])
+            .map(|t| t.0);
+
+        assert_eq!(reparsed, Ok(Action::Map(keys)));
+    }
+
+    #[test]
+    fn action_parses_map_with_new_key_names_and_modifiers() {
+        let text = "<F10><F13><KPEnter><RCmd><H-x><Fn-Left>";
 
+        let fn_key = KeyboardKey::RawKeyCode(
+            RawKeyCode(key_code::KVK_FUNCTION)
+        );
         let expected = Action::Map(vec![
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('t')),
+                KeyboardKey::KeyCode(
+                    KeyCode::new(autopilot::key::KeyCode::F10)
+                ),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('y')),
+                KeyboardKey::RawKeyCode(RawKeyCode(key_code::KVK_F13)),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('p')),
+                KeyboardKey::RawKeyCode(
+                    RawKeyCode(key_code::KVK_ANSI_KEYPAD_ENTER)
+                ),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('e')),
+                KeyboardKey::RawKeyCode(
+                    RawKeyCode(key_code::KVK_RIGHT_COMMAND)
+                ),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('<')),
-                vec![],
+                KeyboardKey::Character(Character::new('x')),
+                vec![Flag::Help],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('l')),
+                KeyboardKey::Down(Box::new(fn_key.clone())),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('t')),
+                KeyboardKey::KeyCode(
+                    KeyCode::new(autopilot::key::KeyCode::LeftArrow)
+                ),
                 vec![],
             ),
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Character(Character::new('>')),
+                KeyboardKey::Up(Box::new(fn_key)),
                 vec![],
             ),
         ]);
@@ -1105,12 +3979,67 @@ mod tests {
     }
 
     #[test]
-    fn action_parses_map_with_nop() {
-        let text = "<Nop>";
+    fn action_parses_map_with_mouse_actions() {
+        let text = "<click><RightClick><middleclick><scroll:-3><scroll:2>\
+            <move:+10,0><move:-5,-20><moveto:100,200>";
+
+        let expected = Action::Map(
+            vec![
+                KeyboardKey::Click(autopilot::mouse::Button::Left),
+                KeyboardKey::Click(autopilot::mouse::Button::Right),
+                KeyboardKey::Click(autopilot::mouse::Button::Middle),
+                KeyboardKey::Scroll(-3),
+                KeyboardKey::Scroll(2),
+                KeyboardKey::MoveBy(10, 0),
+                KeyboardKey::MoveBy(-5, -20),
+                KeyboardKey::MoveTo(100, 200),
+            ]
+                .into_iter()
+                .map(|key| KeyboardKeyWithModifiers::new(key, vec![]))
+                .collect()
+        );
+        let result = action_map().easy_parse(text).map(|t| t.0);
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn action_with_scroll_too_large_fails() {
+        let text = "<scroll:-2147483648>";
+        let result = action_map().easy_parse(text).map(|t| t.0);
 
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("number too large".into()),
+        ));
+    }
+
+    #[test]
+    fn action_parses_map_with_key_down_and_up() {
+        let text = "<down:D><3*Tab><up:D><down:x>";
+
+        let meta = KeyboardKey::KeyCode(
+            KeyCode::new(autopilot::key::KeyCode::Meta)
+        );
+        let tab = KeyboardKeyWithModifiers::new(
+            KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Tab)),
+            vec![],
+        );
         let expected = Action::Map(vec![
             KeyboardKeyWithModifiers::new(
-                KeyboardKey::Nop,
+                KeyboardKey::Down(Box::new(meta.clone())),
+                vec![],
+            ),
+            tab.clone(),
+            tab.clone(),
+            tab,
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Up(Box::new(meta)),
+                vec![],
+            ),
+            KeyboardKeyWithModifiers::new(
+                KeyboardKey::Down(Box::new(
+                    KeyboardKey::Character(Character::new('x'))
+                )),
                 vec![],
             ),
         ]);
@@ -1119,12 +4048,27 @@ mod tests {
         assert_eq!(result, Ok(expected));
     }
 
+    #[test]
+    fn map_parses_repeat_attribute() {
+        let text = "map repeat <up> <Nop>
+cmd repeat=150 <down> echo
+";
+        let result = (map().skip(newline()), map())
+            .easy_parse(text)
+            .map(|t| ((t.0).0.repeat, (t.0).1.repeat));
+
+        assert_eq!(result, Ok((Some(0), Some(150))));
+    }
+
     #[test]
     fn map_parses_map_line() {
         let text = "map <play><down> test
 ";
         let expected = Map {
-            trigger: vec![HeadphoneButton::Play, HeadphoneButton::Down],
+            span: Span::default(),
+            trigger: unresolved(
+                vec![HeadphoneButton::Play, HeadphoneButton::Down]
+            ),
             action: Action::Map(vec![
                 KeyboardKeyWithModifiers::new(
                     KeyboardKey::Character(Character::new('t')),
@@ -1144,6 +4088,7 @@ mod tests {
                 ),
             ]),
             kind: MapKind::Map,
+            repeat: None,
         };
         let result = map().parse(text).map(|t| t.0);
 
@@ -1178,6 +4123,7 @@ cmd <down> echo test
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
             }
         );
         expected.insert(
@@ -1185,9 +4131,12 @@ cmd <down> echo test
             MapAction {
                 action: Action::String("echo test".to_owned()),
                 kind: MapKind::Command,
+                repeat: None,
             }
         );
-        let result = maps().easy_parse(text).map(|t| t.0);
+        let result = maps()
+            .easy_parse(text)
+            .map(|t| resolve_maps(t.0, &Aliases::new(), &mut vec![]).unwrap());
 
         assert_eq!(result, Ok(expected));
     }
@@ -1224,7 +4173,9 @@ map <play> salt and pepper
 # Another comment
 cmd <down> /usr/bin/say 'hello'
 ";
-        let result = map_collection().easy_parse(text).map(|t| t.0);
+        let result = map_collection()
+            .easy_parse(text)
+            .map(|t| resolve_maps(t.0, &Aliases::new(), &mut vec![]).unwrap());
 
         let mut expected = HashMap::new();
         expected.insert(
@@ -1249,6 +4200,7 @@ cmd <down> /usr/bin/say 'hello'
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         expected.insert(
@@ -1317,6 +4269,7 @@ cmd <down> /usr/bin/say 'hello'
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         expected.insert(
@@ -1324,6 +4277,7 @@ cmd <down> /usr/bin/say 'hello'
             MapAction {
                 action: Action::String("/usr/bin/say 'hello'".to_owned()),
                 kind: MapKind::Command,
+                repeat: None,
             },
         );
 
@@ -1336,11 +4290,17 @@ cmd <down> /usr/bin/say 'hello'
 	cmd <up><play> echo hello
 	map <down> insert {}
   	}";
-        let result = mode().parse(text).map(|t| t.0);
+        let result = mode()
+            .parse(text)
+            .map(|t| t.0.resolve(&Aliases::new(), &mut vec![]).unwrap());
 
         let mut expected = Mode {
             trigger: vec![HeadphoneButton::Down, HeadphoneButton::Up],
+            name: None,
             maps: HashMap::new(),
+            on_enter: None,
+            on_exit: None,
+            options: ModeOptions::default(),
         };
 
         expected.maps.insert(
@@ -1348,6 +4308,7 @@ cmd <down> /usr/bin/say 'hello'
             MapAction {
                 action: Action::String("echo hello".to_owned()),
                 kind: MapKind::Command,
+                repeat: None,
             },
         );
         expected.maps.insert(
@@ -1392,12 +4353,169 @@ cmd <down> /usr/bin/say 'hello'
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
+            },
+        );
+
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn mode_parses_enter_and_exit_actions() {
+        let text = "mode <play> {
+    on_enter cmd afplay /System/Library/Sounds/Pop.aiff
+    on_exit map <Esc>
+    map <up> k
+}";
+        let result = mode()
+            .easy_parse(text)
+            .map(|t| t.0.resolve(&Aliases::new(), &mut vec![]).unwrap());
+
+        let mut expected = Mode {
+            trigger: vec![HeadphoneButton::Play],
+            name: None,
+            maps: HashMap::new(),
+            on_enter: Some(MapAction {
+                action: Action::String(
+                    "afplay /System/Library/Sounds/Pop.aiff".to_owned()
+                ),
+                kind: MapKind::Command,
+                repeat: None,
+            }),
+            on_exit: Some(MapAction {
+                action: Action::Map(vec![
+                    KeyboardKeyWithModifiers::new(
+                        KeyboardKey::KeyCode(KeyCode::new(autopilot::key::KeyCode::Escape)),
+                        vec![],
+                    ),
+                ]),
+                kind: MapKind::Map,
+                repeat: None,
+            }),
+            options: ModeOptions::default(),
+        };
+
+        expected.maps.insert(
+            vec![HeadphoneButton::Up],
+            MapAction {
+                action: Action::Map(vec![
+                    KeyboardKeyWithModifiers::new(
+                        KeyboardKey::Character(Character::new('k')),
+                        vec![],
+                    ),
+                ]),
+                kind: MapKind::Map,
+                repeat: None,
             },
         );
 
         assert_eq!(result, Ok(expected));
     }
 
+    #[test]
+    fn mode_parses_enter_and_exit_actions_after_maps() {
+        let text = "mode <play> {
+    map <up> k
+    on_enter cmd echo entered
+    map <down> j
+    on_exit cmd echo exited
+}";
+        let result = mode()
+            .easy_parse(text)
+            .map(|t| t.0.resolve(&Aliases::new(), &mut vec![]).unwrap());
+        let mode = result.unwrap();
+
+        assert_eq!(mode.maps.len(), 2);
+        assert_eq!(
+            mode.on_enter.map(|map_action| map_action.action),
+            Some(Action::String("echo entered".to_owned())),
+        );
+        assert_eq!(
+            mode.on_exit.map(|map_action| map_action.action),
+            Some(Action::String("echo exited".to_owned())),
+        );
+    }
+
+    #[test]
+    fn mode_parses_a_mode_without_maps() {
+        let text = "mode \"Quiet\" <play> {
+    on_enter cmd echo quiet
+}";
+        let result = mode()
+            .easy_parse(text)
+            .map(|t| t.0.resolve(&Aliases::new(), &mut vec![]).unwrap());
+        let quiet = result.unwrap();
+
+        assert!(quiet.maps.is_empty());
+        assert!(quiet.on_enter.is_some());
+
+        let result = mode().easy_parse("mode <play> {}").map(|t| t.0);
+
+        assert!(result.unwrap().maps.is_empty());
+    }
+
+    #[test]
+    fn mode_parses_timeout_attribute() {
+        let text = "mode <play><up> timeout=5000 {
+    map <down> <Nop>
+}";
+        let result = mode().easy_parse(text).map(|t| t.0.options);
+
+        assert_eq!(result, Ok(ModeOptions {
+            timeout: Some(5000),
+            oneshot: false,
+            fallthrough: false,
+        }));
+    }
+
+    #[test]
+    fn mode_parses_multiple_attributes() {
+        let text = "mode <play> oneshot timeout=800 fallthrough {
+    map <down> <Nop>
+}";
+        let result = mode().easy_parse(text).map(|t| t.0.options);
+
+        assert_eq!(result, Ok(ModeOptions {
+            timeout: Some(800),
+            oneshot: true,
+            fallthrough: true,
+        }));
+    }
+
+    #[test]
+    fn mode_parses_timeout_over_a_minute() {
+        let text = "mode <play> timeout=70000 {
+    map <down> <Nop>
+}";
+        let result = mode().easy_parse(State::new(text))
+            .map(|t| t.0.options.timeout);
+
+        assert_eq!(result, Ok(Some(70000)));
+    }
+
+    #[test]
+    fn mode_with_timeout_too_large_fails() {
+        let text = "mode <play> timeout=5000000000 {
+    map <down> <Nop>
+}";
+        let result = mode().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert!(error.errors.contains(
+            &easy::Error::Message("number of milliseconds too large".into()),
+        ));
+    }
+
+    #[test]
+    fn mode_parses_mode_name() {
+        let text = "mode \"Editing\" <play><up> {
+    map <down> <Nop>
+}";
+        let result = mode().easy_parse(text).map(|t| t.0.name);
+
+        assert_eq!(result, Ok(Some(CString::new("Editing").unwrap())));
+    }
+
     #[test]
     fn definitions_parses_modes_and_maps() {
         let text = "
@@ -1414,31 +4532,27 @@ map <down> k
 ";
         let result = definitions().easy_parse(text).map(|t| t.0);
 
-        let mut mode_up_maps = HashMap::new();
-        mode_up_maps.insert(
-            vec![HeadphoneButton::Down],
-            MapAction {
-                action: Action::String("j".to_owned()),
-                kind: MapKind::Command,
-            }
-        );
-
-        let mut mode_down_up_maps = HashMap::new();
-        mode_down_up_maps.insert(
-            vec![HeadphoneButton::Down],
-            MapAction {
-                action: Action::String("j".to_owned()),
-                kind: MapKind::Command,
-            }
-        );
-
         let expected = vec![
-            Definition::Mode(Mode {
-                trigger: vec![HeadphoneButton::Up],
-                maps: mode_up_maps,
+            Definition::Mode(ModeDefinition {
+                span: Span::default(),
+                trigger: unresolved(vec![HeadphoneButton::Up]),
+                name: None,
+                maps: vec![
+                    Map {
+                        span: Span::default(),
+                        trigger: unresolved(vec![HeadphoneButton::Down]),
+                        action: Action::String("j".to_owned()),
+                        kind: MapKind::Command,
+                        repeat: None,
+                    },
+                ],
+                on_enter: None,
+                on_exit: None,
+                options: ModeOptions::default(),
             }),
             Definition::Map(Map {
-                trigger: vec![HeadphoneButton::Play],
+                span: Span::default(),
+                trigger: unresolved(vec![HeadphoneButton::Play]),
                 action: Action::Map(vec![
                     KeyboardKeyWithModifiers::new(
                         KeyboardKey::Character(Character::new('m')),
@@ -1446,13 +4560,30 @@ map <down> k
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
             }),
-            Definition::Mode(Mode {
-                trigger: vec![HeadphoneButton::Down, HeadphoneButton::Up],
-                maps: mode_down_up_maps,
+            Definition::Mode(ModeDefinition {
+                span: Span::default(),
+                trigger: unresolved(
+                    vec![HeadphoneButton::Down, HeadphoneButton::Up]
+                ),
+                name: None,
+                maps: vec![
+                    Map {
+                        span: Span::default(),
+                        trigger: unresolved(vec![HeadphoneButton::Down]),
+                        action: Action::String("j".to_owned()),
+                        kind: MapKind::Command,
+                        repeat: None,
+                    },
+                ],
+                on_enter: None,
+                on_exit: None,
+                options: ModeOptions::default(),
             }),
             Definition::Map(Map {
-                trigger: vec![HeadphoneButton::Down],
+                span: Span::default(),
+                trigger: unresolved(vec![HeadphoneButton::Down]),
                 action: Action::Map(vec![
                     KeyboardKeyWithModifiers::new(
                         KeyboardKey::Character(Character::new('k')),
@@ -1460,6 +4591,7 @@ map <down> k
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
             }),
         ];
 
@@ -1479,10 +4611,10 @@ mode <down><up> {
 
 cmd <play> /usr/bin/say hello
 ";
-        let result = map_group().easy_parse(text).map(|t| t.0);
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
 
         let mut maps: MapCollection = HashMap::new();
-        let mut modes: HashMap<Trigger, MapCollection> = HashMap::new();
+        let mut modes: HashMap<Trigger, Mode> = HashMap::new();
         let mut mode_maps: MapCollection = HashMap::new();
 
         maps.insert(
@@ -1495,6 +4627,7 @@ cmd <play> /usr/bin/say hello
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         maps.insert(
@@ -1502,6 +4635,7 @@ cmd <play> /usr/bin/say hello
             MapAction {
                 action: Action::String("/bin/echo nothing".to_owned()),
                 kind: MapKind::Command,
+                repeat: None,
             },
         );
         maps.insert(
@@ -1509,6 +4643,7 @@ cmd <play> /usr/bin/say hello
             MapAction {
                 action: Action::String("/usr/bin/say hello".to_owned()),
                 kind: MapKind::Command,
+                repeat: None,
             },
         );
 
@@ -1522,16 +4657,27 @@ cmd <play> /usr/bin/say hello
                     ),
                 ]),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         modes.insert(
             vec![HeadphoneButton::Down, HeadphoneButton::Up],
-            mode_maps,
+            Mode {
+                trigger: vec![HeadphoneButton::Down, HeadphoneButton::Up],
+                name: None,
+                maps: mode_maps,
+                on_enter: None,
+                on_exit: None,
+                options: ModeOptions::default(),
+            },
         );
 
         let expected = MapGroup {
             maps: maps,
             modes: modes,
+            escape: None,
+            conflict: ConflictPolicy::MapFirst,
+            devices: HashMap::new(),
         };
 
         assert_eq!(result, Ok(expected));
@@ -1542,7 +4688,7 @@ cmd <play> /usr/bin/say hello
         let text = "
 map <play> x
 ";
-        let result = map_group().easy_parse(text).map(|t| t.0);
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
 
         let mut maps: MapCollection = HashMap::new();
         maps.insert(
@@ -1555,6 +4701,7 @@ map <play> x
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         maps.insert(
@@ -1567,6 +4714,7 @@ map <play> x
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         maps.insert(
@@ -1579,11 +4727,15 @@ map <play> x
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         let expected = MapGroup {
             maps: maps,
             modes: HashMap::new(),
+            escape: None,
+            conflict: ConflictPolicy::MapFirst,
+            devices: HashMap::new(),
         };
 
         assert_eq!(result, Ok(expected));
@@ -1595,7 +4747,7 @@ map <play> x
 
 cmd <down> echo test
 ";
-        let result = map_group().easy_parse(text).map(|t| t.0);
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
 
         let mut maps: MapCollection = HashMap::new();
         maps.insert(
@@ -1608,6 +4760,7 @@ cmd <down> echo test
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         maps.insert(
@@ -1620,6 +4773,7 @@ cmd <down> echo test
                     )]
                 ),
                 kind: MapKind::Map,
+                repeat: None,
             },
         );
         maps.insert(
@@ -1627,11 +4781,15 @@ cmd <down> echo test
             MapAction {
                 action: Action::String("echo test".to_owned()),
                 kind: MapKind::Command,
+                repeat: None,
             },
         );
         let expected = MapGroup {
             maps: maps,
             modes: HashMap::new(),
+            escape: None,
+            conflict: ConflictPolicy::MapFirst,
+            devices: HashMap::new(),
         };
 
         assert_eq!(result, Ok(expected));
@@ -1640,7 +4798,7 @@ cmd <down> echo test
     #[test]
     fn map_group_empty_input_does_not_fail() {
         let text = "";
-        let result = map_group().easy_parse(text).map(|t| t.0);
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
         let expected = MapGroup::default();
 
         assert_eq!(result, Ok(expected));
@@ -1652,7 +4810,7 @@ cmd <down> echo test
 # test
     # a test
     ";
-        let result = map_group().easy_parse(text).map(|t| t.0);
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
         let expected = MapGroup::default();
 
         assert_eq!(result, Ok(expected));
@@ -1672,8 +4830,17 @@ not-a-kind <play> <Nop>
             },
             errors: vec![
                 easy::Error::Unexpected('n'.into()),
+                easy::Error::Expected("escape".into()),
+                easy::Error::Expected("conflict".into()),
+                easy::Error::Expected("alias".into()),
+                easy::Error::Expected("device".into()),
                 easy::Error::Expected("map".into()),
                 easy::Error::Expected("cmd".into()),
+                easy::Error::Expected("text".into()),
+                easy::Error::Expected("do".into()),
+                easy::Error::Expected("if".into()),
+                easy::Error::Expected("toggle".into()),
+                easy::Error::Expected("cycle".into()),
                 easy::Error::Expected("mode".into()),
                 easy::Error::Expected("lf newline".into()),
                 easy::Error::Expected("whitespace".into()),
@@ -1684,6 +4851,332 @@ not-a-kind <play> <Nop>
         }));
     }
 
+    #[test]
+    fn map_group_with_duplicate_mode_name_fails() {
+        let text = "mode \"Editing\" <up> {
+    map <down> <Nop>
+}
+
+mode \"Editing\" <down> {
+    map <up> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 5,
+                column: 1,
+            }
+        );
+        assert!(error.errors.contains(
+            &easy::Error::Message("mode 'Editing' defined twice".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_undefined_mode_name_fails() {
+        let text = "map <up> <mode:Editing>
+mode \"Navigation\" <play> {
+    map <up> <exitmode>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 1,
+                column: 16,
+            }
+        );
+
+        assert!(error.errors.contains(
+            &easy::Error::Message("no mode named 'Editing'".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_undefined_mode_name_in_do_block_fails() {
+        let text = "do <up> { wait 100; keys <mode:Editing>; }";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 1,
+                column: 32,
+            }
+        );
+
+        assert!(error.errors.contains(
+            &easy::Error::Message("no mode named 'Editing'".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_undefined_mode_name_in_if_branch_fails() {
+        let text = "if <up> `true` { keys <Nop>; } else { keys <mode:Editing>; }";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 1,
+                column: 50,
+            }
+        );
+
+        assert!(error.errors.contains(
+            &easy::Error::Message("no mode named 'Editing'".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_undefined_mode_name_in_cycle_fails() {
+        let text = "cycle <up> { keys <Nop>; } { keys <Nop>; } {
+    keys <mode:Editing>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 2,
+                column: 16,
+            }
+        );
+
+        assert!(error.errors.contains(
+            &easy::Error::Message("no mode named 'Editing'".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_parses_escape() {
+        let text = "escape <play><play><play>
+map <up><up> <Nop>
+";
+        let result = map_group()
+            .easy_parse(State::new(text))
+            .map(|t| t.0.escape);
+
+        assert_eq!(
+            result,
+            Ok(Some(vec![
+                HeadphoneButton::Play,
+                HeadphoneButton::Play,
+                HeadphoneButton::Play,
+            ]))
+        );
+    }
+
+    #[test]
+    fn map_group_with_shadowed_escape_fails() {
+        let text = "escape <play><play>
+mode <up> {
+    map <play><play> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 3,
+                column: 5,
+            }
+        );
+        assert!(error.errors.contains(
+            &easy::Error::Message("escape trigger is shadowed by a mapping".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_escape_shadowed_by_default_map_fails() {
+        let text = "map <up><up> <Nop>
+escape <play>
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 2,
+                column: 1,
+            }
+        );
+        assert!(error.errors.contains(
+            &easy::Error::Message("escape trigger is shadowed by a mapping".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_parses_conflict_policy() {
+        let text = "conflict = mode-only
+map <up><up> <Nop>
+";
+        let result = map_group()
+            .easy_parse(State::new(text))
+            .map(|t| t.0.conflict);
+
+        assert_eq!(result, Ok(ConflictPolicy::ModeOnly));
+    }
+
+    #[test]
+    fn map_group_with_conflict_error_fails_on_map_and_mode() {
+        let text = "conflict=error
+map <up><up> <Nop>
+mode <up><up> {
+    map <down> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 3,
+                column: 1,
+            }
+        );
+        assert!(error.errors.contains(
+            &easy::Error::Message("trigger is both a map and a mode".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_conflict_error_shows_error_at_map_after_mode() {
+        let text = "conflict = error
+mode <up><up> {
+    map <down> <Nop>
+}
+
+device \"Pedal\" {
+    map <up><up> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 7,
+                column: 5,
+            }
+        );
+        assert!(error.errors.contains(
+            &easy::Error::Message("trigger is both a map and a mode".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_conflict_error_replaces_default_map_with_mode() {
+        let text = "conflict = error
+mode <play> {
+    map <down> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let map_group = result.unwrap();
+
+        assert!(!map_group.maps.contains_key(&vec![HeadphoneButton::Play]));
+        assert!(map_group.modes.contains_key(&vec![HeadphoneButton::Play]));
+    }
+
+    #[test]
+    fn map_group_parses_aliases_in_triggers() {
+        let text = "alias pedal-left = 7
+map <pedal-left><Pedal-Left:double> <Nop>
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let map_group = result.unwrap();
+
+        assert!(map_group.maps.contains_key(&vec![
+            HeadphoneButton(7),
+            HeadphoneButton(7).double().unwrap(),
+        ]));
+    }
+
+    #[test]
+    fn map_group_parses_aliases_defined_after_use() {
+        let text = "mode <pedal> {
+    map <play+pedal> <Nop>
+}
+alias pedal = 2
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let map_group = result.unwrap();
+        let mode = &map_group.modes[&vec![HeadphoneButton::Down]];
+
+        assert!(mode.maps.contains_key(&vec![HeadphoneButton::PlayDown]));
+    }
+
+    #[test]
+    fn map_group_with_unknown_button_alias_fails() {
+        let text = "map <up> <Up>
+map <not-a-button> <Nop>
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message(
+                "unknown button alias 'not-a-button'".into()
+            ),
+        ));
+    }
+
+    #[test]
+    fn parse_trigger_with_alias_fails() {
+        let result = parse_trigger("<play><pedal>");
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("unknown button alias 'pedal'".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_with_alias_of_builtin_button_fails() {
+        let text = "alias play = 7
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+
+        assert!(result.unwrap_err().errors.contains(
+            &easy::Error::Message("can't alias a built-in button name".into()),
+        ));
+    }
+
+    #[test]
+    fn map_group_parses_device_blocks() {
+        let text = "device \"AirPods\" {
+    map <up> <Nop>
+}
+
+device \"Pedal\" {
+    cmd <button:3> echo
+}
+
+device \"AirPods\" {
+    map <down> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let map_group = result.unwrap();
+
+        let airpods = &map_group.devices[&CString::new("AirPods").unwrap()];
+        let pedal = &map_group.devices[&CString::new("Pedal").unwrap()];
+
+        assert_eq!(airpods.len(), 2);
+        assert!(pedal.contains_key(&vec![HeadphoneButton(3)]));
+    }
+
     #[test]
     fn map_group_shows_error_in_trigger() {
         let text = "map <up> <Up>
@@ -1704,6 +5197,32 @@ map <not-a-button> <Nop>
         ));
     }
 
+    #[test]
+    fn map_group_shows_error_in_chord_with_alias() {
+        let text = "alias pedal = 7
+
+mode <up> {
+    map <play+pedal> <Nop>
+}
+";
+        let result = map_group().easy_parse(State::new(text)).map(|t| t.0);
+        let error = result.unwrap_err();
+
+        assert_eq!(
+            error.position,
+            SourcePosition {
+                line: 4,
+                column: 10,
+            }
+        );
+        assert!(error.errors.contains(
+            &easy::Error::Message(
+                "chords can only combine different play, up, and down buttons"
+                    .into()
+            ),
+        ));
+    }
+
     #[test]
     fn map_group_shows_error_in_action() {
         let text = "map <up> <Up>
@@ -1747,4 +5266,68 @@ mode <play> {
             &easy::Error::Message("missing closing '}'".into()),
         ));
     }
+
+    /// Generate a mappings file with `count` maps whose actions cycle through
+    /// every key name.
+    fn generated_mappings(count: usize) -> String {
+        let buttons = ["<play>", "<up>", "<down>"];
+        let mut mappings = String::new();
+
+        for i in 0..count {
+            let mut n = i;
+            let mut trigger = String::new();
+
+            // Nine buttons give 3^9 unique triggers
+            for _ in 0..9 {
+                trigger.push_str(buttons[n % 3]);
+                n /= 3;
+            }
+
+            let key = &key_name::KEY_NAMES[i % key_name::KEY_NAMES.len()];
+
+            mappings.push_str(
+                &format!(
+                    "map {} <C-{}>text<3*{}><U+00E9><wait:1>\n",
+                    trigger,
+                    key.name,
+                    key.name,
+                )
+            );
+        }
+
+        mappings
+    }
+
+    // Run with `make bench`.
+    #[test]
+    #[ignore]
+    fn bench_map_group_parse_time_grows_linearly() {
+        use std::time::Instant;
+
+        // Microseconds per map
+        let time_per_map = |count: usize| -> f64 {
+            let text = generated_mappings(count);
+
+            let start = Instant::now();
+            let map_group = MapGroup::parse(&text).unwrap();
+            let elapsed = start.elapsed();
+
+            // Plus the default maps
+            assert!(map_group.maps.len() >= count);
+
+            (elapsed.as_secs() as f64 * 1e6
+                + f64::from(elapsed.subsec_micros())) / count as f64
+        };
+
+        let small = time_per_map(1_000);
+        let large = time_per_map(19_683);
+
+        println!(
+            "1000 maps: {:.2} µs/map, 19683 maps: {:.2} µs/map",
+            small,
+            large,
+        );
+
+        assert!(large < small * 2.0);
+    }
 }
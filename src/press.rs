@@ -0,0 +1,544 @@
+// Copyright (c) 2018 Teddy Wing
+//
+// This file is part of DomeKey.
+//
+// *Purchasing policy notice:* All users of the software are expected to
+// purchase a license from Teddy Wing unless they have a good reason not to
+// pay. Users who can't purchase a license may apply to receive one for free
+// at inquiry@domekey.teddywing.com. Users are free to:
+//
+// * download, build, and modify the app;
+// * share the modified source code;
+// * share the purchased or custom-built binaries (with unmodified license
+//   and contact info), provided that the purchasing policy is explained to
+//   all potential users.
+//
+// This software is available under a modified version of the Open Community
+// Indie Software License:
+//
+// Permission to use, copy, modify, and/or distribute this software for any
+// purpose is hereby granted, subject to the following conditions:
+//
+// * all copies retain the above copyright notice, the above purchasing
+//   policy notice and this permission notice unmodified;
+//
+// * all copies retain the name of the software (DomeKey), the name of the
+//   author (Teddy Wing), and contact information (including, but not limited
+//   to, inquiry@domekey.teddywing.com, and domekey.teddywing.com URLs)
+//   unmodified;
+//
+// * no fee is charged for distribution of the software;
+//
+// * the best effort is made to explain the purchasing policy to all users of
+//   the software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", AND THE AUTHOR AND COPYRIGHT HOLDERS
+// DISCLAIM ALL WARRANTIES, EXPRESS OR IMPLIED, WITH REGARD TO THIS SOFTWARE,
+// INCLUDING BUT NOT LIMITED TO WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE. IN NO EVENT SHALL THE AUTHOR OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY
+// DAMAGES WHATSOEVER RESULTING FROM LOSS OF USE, DATA, OR PROFITS, WHETHER
+// IN AN ACTION OF CONTRACT, NEGLIGENCE, OR OTHER TORTIOUS ACTION, ARISING
+// OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+use HeadphoneButton;
+use config::Milliseconds;
+use errors::*;
+use ffi::State;
+use map::{OnModeChange, current_map, run_key_action};
+
+/// Milliseconds since an arbitrary point in time chosen by the host.
+pub type Timestamp = u64;
+
+/// Buttons pressed and released by the host that haven't yet been run as a
+/// trigger.
+#[derive(Debug, Default)]
+pub struct Presses {
+    /// The button currently held down.
+    pressed: Option<Press>,
+
+    /// A short press that could still become the first half of a double
+    /// press.
+    released: Option<(HeadphoneButton, Timestamp)>,
+
+    /// Resolved buttons in the trigger being built.
+    trigger: Vec<HeadphoneButton>,
+    last_event: Option<Timestamp>,
+
+    /// A held button whose `repeat` mapping is being run.
+    repeating: Option<Repeat>,
+}
+
+#[derive(Debug)]
+struct Repeat {
+    button: HeadphoneButton,
+    interval: Milliseconds,
+    last_run: Timestamp,
+}
+
+#[derive(Debug)]
+struct Press {
+    button: HeadphoneButton,
+    at: Timestamp,
+
+    /// Whether this is the second press of a double press.
+    double: bool,
+}
+
+/// Record `button` being pressed down at `timestamp`. A button pressed
+/// within the chord window of a held button forms a chord with it,
+/// otherwise the held button is added to the trigger first. If the
+/// button starts a trigger and is mapped with `repeat`, its action is run
+/// immediately and then repeated until the button is released.
+pub fn button_press(
+    state: &mut State,
+    button: HeadphoneButton,
+    timestamp: Timestamp,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    let chord_window = state.chord_window;
+
+    if let Some(ref mut press) = state.presses.pressed {
+        if !press.double && elapsed(press.at, timestamp) <= chord_window {
+            if let Some(chord) = press.button.chord(&button) {
+                press.button = chord;
+
+                return Ok(());
+            }
+        }
+    }
+
+    // A button still held outside the chord window resolves now, as if it
+    // had been released, so it isn't lost from the trigger. Its release is
+    // ignored later.
+    if let Some(press) = state.presses.pressed.take() {
+        let resolved = if press.double {
+            press.button.double()
+        } else if elapsed(press.at, timestamp) >= state.long_press {
+            // Chords have no long press
+            press.button.long().or(Some(press.button))
+        } else {
+            Some(press.button)
+        };
+
+        if let Some(resolved) = resolved {
+            state.presses.trigger.push(resolved);
+        }
+    }
+
+    if let Some(interval) = repeat_interval(state, &button) {
+        state.presses.repeating = Some(Repeat {
+            button: button.clone(),
+            interval: interval,
+            last_run: timestamp,
+        });
+
+        return run_key_action(state, &[button], on_mode_change);
+    }
+
+    let double_press = state.double_press;
+    let presses = &mut state.presses;
+
+    let double = match presses.released.take() {
+        Some((released, at)) => {
+            if released == button
+                && elapsed(at, timestamp) <= double_press
+            {
+                true
+            } else {
+                presses.trigger.push(released);
+
+                false
+            }
+        },
+        None => false,
+    };
+
+    presses.pressed = Some(Press {
+        button: button,
+        at: timestamp,
+        double: double,
+    });
+    presses.last_event = Some(timestamp);
+
+    Ok(())
+}
+
+/// Get the repeat interval of the mapping for `button` if it has one. Only
+/// buttons that start a trigger can repeat.
+fn repeat_interval(
+    state: &State,
+    button: &HeadphoneButton,
+) -> Option<Milliseconds> {
+    let presses = &state.presses;
+
+    if presses.pressed.is_some()
+        || presses.released.is_some()
+        || !presses.trigger.is_empty()
+    {
+        return None;
+    }
+
+    let map_group = match state.map_group {
+        Some(ref map_group) => map_group,
+        None => return None,
+    };
+
+    current_map(state, map_group, &[button.clone()])
+        .and_then(|map| map.repeat)
+        .map(|interval| match interval {
+            0 => state.repeat_interval,
+            interval => interval,
+        })
+}
+
+/// Record `button` being released at `timestamp`, resolving it to a short,
+/// long, or double press, or a chord. A short press is held back for the
+/// double press threshold only if a mapping uses the button's double press.
+pub fn button_release(
+    state: &mut State,
+    button: HeadphoneButton,
+    timestamp: Timestamp,
+) {
+    let is_repeating = state.presses.repeating
+        .as_ref()
+        .map_or(false, |repeat| repeat.button == button);
+
+    if is_repeating {
+        state.presses.repeating = None;
+
+        return;
+    }
+
+    let press = match state.presses.pressed.take() {
+        Some(press) => {
+            if !press.button.chord_contains(&button) {
+                state.presses.pressed = Some(press);
+
+                return;
+            }
+
+            press
+        },
+        None => return,
+    };
+
+    let uses_double = match (&state.map_group, button.double()) {
+        (&Some(ref map_group), Some(double)) => map_group.uses_button(&double),
+        _ => false,
+    };
+    let presses = &mut state.presses;
+
+    presses.last_event = Some(timestamp);
+
+    let resolved = if press.button != button {
+        // Chords resolve when the first of their buttons is released. The
+        // other buttons' releases are ignored.
+        Some(press.button)
+    } else if press.double {
+        button.double()
+    } else if elapsed(press.at, timestamp) >= state.long_press {
+        button.long()
+    } else if uses_double {
+        presses.released = Some((button, timestamp));
+
+        None
+    } else {
+        Some(button)
+    };
+
+    if let Some(resolved) = resolved {
+        presses.trigger.push(resolved);
+    }
+}
+
+/// Re-run a held `repeat` mapping, resolve short presses once the double
+/// press threshold has passed, and run the trigger once no button has been
+/// pressed for the trigger timeout.
+pub fn check_presses(
+    state: &mut State,
+    timestamp: Timestamp,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    let repeat_button = match state.presses.repeating {
+        Some(ref mut repeat) => {
+            if elapsed(repeat.last_run, timestamp) < repeat.interval {
+                return Ok(());
+            }
+
+            repeat.last_run = timestamp;
+
+            repeat.button.clone()
+        },
+        None => return check_trigger(state, timestamp, on_mode_change),
+    };
+
+    run_key_action(state, &[repeat_button], on_mode_change)
+}
+
+fn check_trigger(
+    state: &mut State,
+    timestamp: Timestamp,
+    on_mode_change: OnModeChange,
+) -> Result<()> {
+    let trigger = {
+        let presses = &mut state.presses;
+
+        if let Some((button, at)) = presses.released.clone() {
+            if elapsed(at, timestamp) > state.double_press {
+                presses.trigger.push(button);
+                presses.released = None;
+            }
+        }
+
+        if presses.pressed.is_some()
+            || presses.released.is_some()
+            || presses.trigger.is_empty()
+        {
+            return Ok(());
+        }
+
+        match presses.last_event {
+            Some(at) if elapsed(at, timestamp) < state.timeout =>
+                return Ok(()),
+            _ => (),
+        }
+
+        presses.last_event = None;
+
+        presses.trigger.split_off(0)
+    };
+
+    run_key_action(state, &trigger, on_mode_change)
+}
+
+/// Milliseconds between `start` and `end`, saturating at the largest
+/// `Milliseconds` value.
+fn elapsed(start: Timestamp, end: Timestamp) -> Milliseconds {
+    let elapsed = end.saturating_sub(start);
+
+    if elapsed > Milliseconds::max_value() as Timestamp {
+        Milliseconds::max_value()
+    } else {
+        elapsed as Milliseconds
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use MapGroup;
+    use map::{ModeChange, ModeInfo};
+    use super::*;
+
+    extern "C" fn ignore_mode_change(
+        _mode_change: ModeChange,
+        _mode: *const ModeInfo,
+    ) {}
+
+    fn state_with_mappings(mappings: &str) -> State {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(MapGroup::parse(mappings).unwrap()));
+        state.timeout = 500;
+        state.long_press = 600;
+        state.double_press = 300;
+        state.chord_window = 50;
+
+        state
+    }
+
+    /// Press and release each button, resolving the result.
+    fn press_and_release(
+        state: &mut State,
+        presses: &[(HeadphoneButton, Timestamp, Timestamp)],
+        now: Timestamp,
+    ) {
+        for &(ref button, down, up) in presses {
+            button_press(state, button.clone(), down, ignore_mode_change)
+                .unwrap();
+            button_release(state, button.clone(), up);
+        }
+
+        check_presses(state, now, ignore_mode_change).unwrap();
+    }
+
+    #[test]
+    fn button_release_resolves_short_press() {
+        let mut state = state_with_mappings("map <up><up> <Nop>");
+        press_and_release(
+            &mut state,
+            &[(HeadphoneButton::Up, 0, 100)],
+            150,
+        );
+
+        assert_eq!(state.presses.trigger, vec![HeadphoneButton::Up]);
+    }
+
+    #[test]
+    fn default_state_uses_default_press_timings_without_a_config() {
+        let mut state = State::default();
+        state.map_group = Some(Rc::new(
+            MapGroup::parse("map <up><up> <Nop>").unwrap()
+        ));
+
+        press_and_release(
+            &mut state,
+            &[
+                (HeadphoneButton::Up, 0, 100),
+                (HeadphoneButton::Down, 150, 200),
+            ],
+            250,
+        );
+
+        // Short presses, waiting for the trigger timeout
+        assert_eq!(
+            state.presses.trigger,
+            vec![HeadphoneButton::Up, HeadphoneButton::Down]
+        );
+    }
+
+    #[test]
+    fn button_release_resolves_long_press() {
+        let mut state = state_with_mappings("map <up><up> <Nop>");
+        press_and_release(
+            &mut state,
+            &[(HeadphoneButton::Up, 0, 700)],
+            750,
+        );
+
+        assert_eq!(state.presses.trigger, vec![HeadphoneButton::UpLong]);
+    }
+
+    #[test]
+    fn button_press_resolves_double_press_if_mapped() {
+        let mut state = state_with_mappings("map <up:double><up> <Nop>");
+        press_and_release(
+            &mut state,
+            &[
+                (HeadphoneButton::Up, 0, 100),
+                (HeadphoneButton::Up, 200, 300),
+            ],
+            350,
+        );
+
+        assert_eq!(state.presses.trigger, vec![HeadphoneButton::UpDouble]);
+    }
+
+    #[test]
+    fn button_press_without_double_mapping_resolves_two_short_presses() {
+        let mut state = state_with_mappings("map <up><up><up> <Nop>");
+        press_and_release(
+            &mut state,
+            &[
+                (HeadphoneButton::Up, 0, 100),
+                (HeadphoneButton::Up, 200, 300),
+            ],
+            350,
+        );
+
+        assert_eq!(
+            state.presses.trigger,
+            vec![HeadphoneButton::Up, HeadphoneButton::Up]
+        );
+    }
+
+    #[test]
+    fn button_press_within_chord_window_resolves_chord() {
+        let mut state = state_with_mappings("map <play+up> <Nop>");
+
+        button_press(&mut state, HeadphoneButton::Up, 0, ignore_mode_change)
+            .unwrap();
+        button_press(&mut state, HeadphoneButton::Play, 30, ignore_mode_change)
+            .unwrap();
+        button_release(&mut state, HeadphoneButton::Play, 100);
+        button_release(&mut state, HeadphoneButton::Up, 110);
+
+        assert_eq!(state.presses.trigger, vec![HeadphoneButton::PlayUp]);
+    }
+
+    #[test]
+    fn button_press_outside_chord_window_is_not_a_chord() {
+        let mut state = state_with_mappings("map <play+up> <Nop>");
+
+        button_press(&mut state, HeadphoneButton::Up, 0, ignore_mode_change)
+            .unwrap();
+        button_press(&mut state, HeadphoneButton::Play, 80, ignore_mode_change)
+            .unwrap();
+        button_release(&mut state, HeadphoneButton::Play, 150);
+        button_release(&mut state, HeadphoneButton::Up, 160);
+
+        assert_eq!(
+            state.presses.trigger,
+            vec![HeadphoneButton::Up, HeadphoneButton::Play]
+        );
+    }
+
+    #[test]
+    fn check_presses_resolves_short_press_after_double_press_threshold() {
+        let mut state = state_with_mappings("map <up:double><up> <Nop>");
+        press_and_release(
+            &mut state,
+            &[(HeadphoneButton::Up, 0, 100)],
+            450,
+        );
+
+        assert_eq!(state.presses.trigger, vec![HeadphoneButton::Up]);
+    }
+
+    #[test]
+    fn check_presses_runs_trigger_after_timeout() {
+        let mut state = state_with_mappings(
+            "mode <play:long> {
+    map <up> <Nop>
+}
+"
+        );
+        press_and_release(
+            &mut state,
+            &[(HeadphoneButton::Play, 0, 700)],
+            1200,
+        );
+
+        assert!(state.presses.trigger.is_empty());
+        assert_eq!(state.in_mode, Some(vec![HeadphoneButton::PlayLong]));
+    }
+
+    static REPEATS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_repeats(
+        _mode_change: ModeChange,
+        _mode: *const ModeInfo,
+    ) {
+        REPEATS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn check_presses_repeats_held_button_until_release() {
+        // Count mode changes to observe each run of the action without
+        // sending key events
+        let mut state = state_with_mappings(
+            "map repeat=100 <up> <mode:Counter>
+
+mode \"Counter\" <down><down> {
+    map <up> <exitmode>
+}
+"
+        );
+
+        button_press(&mut state, HeadphoneButton::Up, 0, count_repeats)
+            .unwrap();
+
+        for now in &[50, 100, 150, 200, 250] {
+            check_presses(&mut state, *now, count_repeats).unwrap();
+        }
+
+        button_release(&mut state, HeadphoneButton::Up, 260);
+        check_presses(&mut state, 300, count_repeats).unwrap();
+        check_presses(&mut state, 1000, count_repeats).unwrap();
+
+        assert_eq!(REPEATS.load(Ordering::SeqCst), 3);
+        assert!(state.presses.trigger.is_empty());
+    }
+}